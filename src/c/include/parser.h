@@ -1 +1,5 @@
 #include <libxml/parser.h>
+#include <libxml/HTMLparser.h>
+#include <libxml/parserInternals.h>
+#include <libxml/xmlschemas.h>
+#include <libxml/relaxng.h>
@@ -19,6 +19,17 @@
 #ifndef __SAX_FUNCTIONS_H__
 #define __SAX_FUNCTIONS_H__
 
+enum sax_severity {
+	SAX_WARNING,
+	SAX_ERROR,
+	SAX_FATAL_ERROR,
+};
+
+/* Receives the formatted messages of the error callbacks below */
+typedef void (*sax_report_func)(void *user_data_ptr, enum sax_severity severity, const char *msg);
+
+void sax_set_report_func(sax_report_func func);
+
 void sax_warning(void *user_data_ptr, const char *msg, ...);
 
 void sax_error(void *user_data_ptr, const char *msg, ...);
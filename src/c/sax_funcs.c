@@ -17,30 +17,60 @@
  */
 
 #include <stdio.h>
+#include <stdlib.h>
 #include <stdarg.h>
 
 #include "sax_funcs.h"
 
-void sax_warning(void* user_data_ptr, const char* msg, ...) {
-	fprintf(stderr, "Warning!: ");
+static sax_report_func report_func = NULL;
+
+void sax_set_report_func(sax_report_func func) {
+	report_func = func;
+}
+
+static void sax_report(void *user_data_ptr, enum sax_severity severity, const char *prefix, const char *msg, va_list args) {
+	va_list args_copy;
+	va_copy(args_copy, args);
+	int len = vsnprintf(NULL, 0, msg, args_copy);
+	va_end(args_copy);
+
+	if (len < 0) {
+		return;
+	}
+
+	char *buf = malloc(len + 1);
+	if (buf == NULL) {
+		return;
+	}
+
+	vsnprintf(buf, len + 1, msg, args);
 
+	if (report_func != NULL) {
+		report_func(user_data_ptr, severity, buf);
+	} else {
+		fprintf(stderr, "%s%s", prefix, buf);
+	}
+
+	free(buf);
+}
+
+void sax_warning(void* user_data_ptr, const char* msg, ...) {
 	va_list args;
 	va_start(args, msg);
-	vfprintf(stderr, msg, args);
+	sax_report(user_data_ptr, SAX_WARNING, "Warning!: ", msg, args);
+	va_end(args);
 }
 
 void sax_error(void* user_data_ptr, const char* msg, ...) {
-	fprintf(stderr, "Error!: ");
-
 	va_list args;
 	va_start(args, msg);
-	vfprintf(stderr, msg, args);
+	sax_report(user_data_ptr, SAX_ERROR, "Error!: ", msg, args);
+	va_end(args);
 }
 
 void sax_fatal_error(void* user_data_ptr, const char* msg, ...) {
-	fprintf(stderr, "Fatal Error!: ");
-
 	va_list args;
 	va_start(args, msg);
-	vfprintf(stderr, msg, args);
+	sax_report(user_data_ptr, SAX_FATAL_ERROR, "Fatal Error!: ", msg, args);
+	va_end(args);
 }
@@ -16,7 +16,7 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use clap::{ App, Arg };
+use clap::{ App, AppSettings, Arg, SubCommand };
 use clap::{ crate_name, crate_version, crate_authors, crate_description };
 
 // Mark the function public so that it can be used by build.rs to generate the
@@ -26,6 +26,8 @@ pub fn build_cli() -> App<'static, 'static> {
 		.version(crate_version!())
 		.author(crate_authors!())
 		.about(crate_description!())
+		// Lets the options given on the command line override the defaults
+		.global_setting(AppSettings::AllArgsOverrideSelf)
 		.arg(
 			Arg::with_name("Print Mappings")
 				.short("p")
@@ -34,88 +36,552 @@ pub fn build_cli() -> App<'static, 'static> {
 					  in the following order <SPACE><TAB><LF> and exits.")
 				.display_order(1)
 		)
+		.args(&whitespace_args())
+		.args(&escape_args())
+		.args(&negated_flags())
 		.arg(
-			Arg::with_name("Map Whitespace")
-				.short("m")
-				.long("map-whitespace")
-				.help("Transliterates whitespace characters to printable characters.")
-				.display_order(2)
+			Arg::with_name("HTML")
+				.long("html")
+				.help("Parse the input with the lenient HTML parser, which adds implied tags such \
+					  as `html` and `body`. Files ending with `.html` or `.htm` are parsed as HTML \
+					  without this flag.")
+				.display_order(11)
 		)
 		.arg(
-			Arg::with_name("Whitespace Mapping")
-				.short("w")
-				.long("whitespace-map")
-				.help("Specifies the whitespace characters are mapped to. \
-					  The characters must be in the following order <SPACE><TAB><LF>. \
-					  Overrides: `--space-char`, `--tab-char`, `--newline-char`")
+			Arg::with_name("Encoding")
+				.long("encoding")
+				.help("Decode the input with the encoding NAME, such as `ISO-8859-1` or \
+					  `Shift_JIS`, instead of the one the document declares or that is detected \
+					  from its first bytes.")
 				.takes_value(true)
-				.value_name("MAP")
-				.overrides_with_all(&["space-char", "tab-char", "newline-char"])
-				.display_order(3)
+				.value_name("NAME")
+				.display_order(12)
 		)
 		.arg(
-			Arg::with_name("Space Character")
-				.long("space-char")
-				.help("Specifies the character <SPACE> is mapped to.")
+			Arg::with_name("Invalid UTF-8")
+				.long("invalid-utf8")
+				.help("What to do with strings that are not valid UTF-8 once decoded: `replace` \
+					  the invalid sequences with U+FFFD (the default), `escape` their bytes as \
+					  `\\xNN` or `fail` with a fatal error.")
 				.takes_value(true)
-				.value_name("CHAR")
-				.display_order(4)
+				.value_name("POLICY")
+				.possible_values(&["replace", "escape", "fail"])
+				.hide_possible_values(true)
+				.display_order(13)
 		)
 		.arg(
-			Arg::with_name("Tab Character")
-				.long("tab-char")
-				.help("Specifies the character <TAB> is mapped to.")
+			Arg::with_name("Huge")
+				.long("huge")
+				.help("Lift the limits on the size of documents, for large documents that are \
+					  trusted. The limits below only apply when given explicitly.")
+				.display_order(14)
+		)
+		.arg(
+			Arg::with_name("Max Depth")
+				.long("max-depth")
+				.help("Fail on documents with elements nested more than N levels deep. \
+					  Default: 1024. libxml2 limits the documents checked against a DTD or a \
+					  RELAX NG schema to 256 levels unless `--huge` is given.")
+				.takes_value(true)
+				.value_name("N")
+				.validator(|s| match s.parse::<usize>() {
+					Ok(n) if n > 0 => Ok(()),
+					_ => Err(String::from("N must be a positive integer")),
+				})
+				.display_order(15)
+		)
+		.arg(
+			Arg::with_name("Max Attributes")
+				.long("max-attributes")
+				.help("Fail on documents with an element that has more than N attributes. \
+					  Default: 256")
+				.takes_value(true)
+				.value_name("N")
+				.validator(|s| match s.parse::<usize>() {
+					Ok(n) if n > 0 => Ok(()),
+					_ => Err(String::from("N must be a positive integer")),
+				})
+				.display_order(16)
+		)
+		.arg(
+			Arg::with_name("Max Text Size")
+				.long("max-text-size")
+				.help("Fail on documents with a text node longer than BYTES. Default: 10000000")
 				.takes_value(true)
-				.value_name("CHAR")
-				.display_order(5)
+				.value_name("BYTES")
+				.validator(|s| match s.parse::<usize>() {
+					Ok(n) if n > 0 => Ok(()),
+					_ => Err(String::from("BYTES must be a positive integer")),
+				})
+				.display_order(17)
+		)
+		.arg(
+			Arg::with_name("Validate")
+				.long("validate")
+				.help("Validate XML documents against the DTD they declare. Validity errors are \
+					  reported like the other errors. The whole document is kept in memory.")
+				.display_order(18)
 		)
 		.arg(
-			Arg::with_name("Newline Character")
-				.long("newline-char")
-				.help("Specifies the character <LF> is mapped to.")
+			Arg::with_name("DTD")
+				.long("dtd")
+				.help("Validate XML documents against the DTD in FILE instead of the one they \
+					  declare. The document is checked once it is parsed, so the errors can't be \
+					  located with `--invalid-paths`.")
 				.takes_value(true)
-				.value_name("CHAR")
-				.display_order(6)
+				.value_name("FILE")
+				.display_order(19)
 		)
 		.arg(
-			Arg::with_name("Compress Whitespace")
-				.short("c")
-				.long("compress-whitespace")
-				.help("Compresses consecutive `space` characters to a `tab` character\
-					  according to the compression level.")
-				.display_order(7)
+			Arg::with_name("XSD")
+				.long("xsd")
+				.help("Validate XML documents against the XML schema in FILE while they are parsed.")
+				.takes_value(true)
+				.value_name("FILE")
+				.display_order(20)
 		)
 		.arg(
-			Arg::with_name("Compression Level")
-				.short("l")
-				.long("compress-level")
-				.help("Specifies the number consecutive spaces compressed to a \
-					  single character. Default: 4 spaces")
+			Arg::with_name("RNG")
+				.long("rng")
+				.help("Validate XML documents against the RELAX NG schema in FILE while they are \
+					  parsed. Only the elements being validated are kept in memory.")
 				.takes_value(true)
-				.value_name("LEVEL")
-				.allow_hyphen_values(true)
-				.display_order(8)
+				.value_name("FILE")
+				.display_order(21)
+		)
+		.arg(
+			Arg::with_name("Invalid Paths")
+				.long("invalid-paths")
+				.help("Print only the paths of the elements found invalid by `--xsd`, `--rng` or \
+					  `--validate`, once each. With `--format jsonl` every error is printed as an \
+					  `invalid` record with its message.")
+				.conflicts_with("DTD")
+				.display_order(22)
 		)
 		.arg(
 			Arg::with_name("Keep All Whitespace")
 				.short("k")
 				.long("keep-all-whitespace")
 				.help("Keep all the empty space between the tags in the final output.")
-				.display_order(9)
+				.display_order(23)
+		)
+		.arg(
+			Arg::with_name("Merge CDATA")
+				.long("merge-cdata")
+				.help("Print CDATA sections as ordinary text instead of `/path/![CDATA[...]]`.")
+				.display_order(24)
+		)
+		.arg(
+			Arg::with_name("Trim")
+				.long("trim")
+				.help("Strip the leading and trailing whitespace of every text and attribute value. \
+					  Text made only of whitespace is dropped.")
+				.display_order(25)
+		)
+		.arg(
+			Arg::with_name("Normalize Space")
+				.long("normalize-space")
+				.help("Trim every text and attribute value, and collapse the runs of whitespace \
+					  inside them to a single space like XPath's `normalize-space()`.")
+				.display_order(26)
+		)
+		.arg(
+			Arg::with_name("Text Buffer Size")
+				.long("text-buffer-size")
+				.help("Print the text of an element on several lines once more than BYTES of it \
+					  are buffered. By default the whole text between two tags is printed on a \
+					  single line.")
+				.takes_value(true)
+				.value_name("BYTES")
+				.validator(|s| match s.parse::<usize>() {
+					Ok(n) if n > 0 => Ok(()),
+					_ => Err(String::from("BYTES must be a positive integer")),
+				})
+				.display_order(27)
+		)
+		.arg(
+			Arg::with_name("Output Format")
+				.short("f")
+				.long("format")
+				.help("Specifies the format of the output. `path` prints `/path@[name=value]` and \
+					  `/path=\"text\"` and can be unflattened. `xml2` prints `/path/@name=value` \
+					  and `/path=text` like the xml2 tool. `jsonl` prints a JSON object per line \
+					  for every element, attribute, text, comment and PI. Default: path")
+				.takes_value(true)
+				.value_name("FORMAT")
+				.possible_values(&["path", "xml2", "jsonl"])
+				.hide_possible_values(true)
+				.display_order(28)
+		)
+		.arg(
+			Arg::with_name("Name Format")
+				.short("n")
+				.long("names")
+				.help("Specifies how element and attribute names are printed. `as-is` keeps \
+					  the prefix, `local` strips it and `clark` prints the namespace URI as \
+					  `{uri}local`. Only `as-is` names can be unflattened. Default: as-is")
+				.takes_value(true)
+				.value_name("FORMAT")
+				.possible_values(&["as-is", "local", "clark"])
+				.hide_possible_values(true)
+				.display_order(29)
+		)
+		.arg(
+			Arg::with_name("Namespace Lines")
+				.long("xmlns-lines")
+				.help("Print namespace declarations on their own lines as `/path/xmlns:prefix=[uri]` \
+					  instead of among the attributes.")
+				.display_order(30)
+		)
+		.arg(
+			Arg::with_name("Sibling Indices")
+				.short("i")
+				.long("indices")
+				.help("Print the position of elements among their siblings with the same name, \
+					  `/a/b[2]`. `always` indexes every element and `repeated` every sibling \
+					  after the first one.")
+				.takes_value(true)
+				.value_name("MODE")
+				.possible_values(&["always", "repeated"])
+				.hide_possible_values(true)
+				.display_order(31)
+		)
+		.arg(
+			Arg::with_name("Line Numbers")
+				.short("L")
+				.long("line-numbers")
+				.help("Prefix every line with the `line:column:` the parser reached when it \
+					  reported the element or text. That is right after the start tag of an \
+					  element, and after the first part libxml2 reported of a text.")
+				.display_order(32)
+		)
+		.arg(
+			Arg::with_name("Byte Offsets")
+				.short("b")
+				.long("byte-offset")
+				.help("Prefix every line with the byte offset at which the parser reported \
+					  the element or text, after the line and column when `--line-numbers` \
+					  is given.")
+				.display_order(33)
+		)
+		.arg(
+			Arg::with_name("With Filename")
+				.short("H")
+				.long("with-filename")
+				.help("Print the name of the file at the start of every line. Default when more \
+					  than one file is given. Lines with file names can't be unflattened.")
+				.overrides_with("No Filename")
+				.display_order(34)
+		)
+		.arg(
+			Arg::with_name("No Filename")
+				.long("no-filename")
+				// What `with-filename = false` in the configuration file turns into
+				.alias("no-with-filename")
+				.help("Don't print the name of the file at the start of every line.")
+				.overrides_with("With Filename")
+				.display_order(35)
+		)
+		.arg(
+			Arg::with_name("Filename Separator")
+				.long("filename-separator")
+				.help("Specifies the string printed between the file name and the rest of the \
+					  line. Default: `:`")
+				.takes_value(true)
+				.value_name("SEP")
+				.display_order(36)
+		)
+		.arg(
+			Arg::with_name("File Markers")
+				.long("file-markers")
+				.help("Print `==> FILE <==` before and `<== FILE ==>` after the output of every \
+					  file. The `jsonl` format prints `file-start` and `file-end` records.")
+				.display_order(37)
+		)
+		.arg(
+			Arg::with_name("Include")
+				.long("include")
+				.help("Only print the elements matching PATTERN and their descendants. \
+					  `*` matches any part of a name, `**` any number of elements and \
+					  patterns not starting with `/` match at any depth, e.g. `/root/**/Job/*`. \
+					  Can be given multiple times.")
+				.takes_value(true)
+				.value_name("PATTERN")
+				.multiple(true)
+				.number_of_values(1)
+				.display_order(38)
+		)
+		.arg(
+			Arg::with_name("Exclude")
+				.long("exclude")
+				.help("Skip the elements matching PATTERN and their descendants. Takes \
+					  precedence over `--include`. Can be given multiple times.")
+				.takes_value(true)
+				.value_name("PATTERN")
+				.multiple(true)
+				.number_of_values(1)
+				.display_order(39)
+		)
+		.arg(
+			Arg::with_name("Quiet")
+				.short("q")
+				.long("quiet")
+				.help("Suppress the warnings and errors reported while parsing. \
+					  The exit status still reflects them.")
+				.display_order(40)
+		)
+		.arg(
+			Arg::with_name("Strict")
+				.long("strict")
+				.help("Treat warnings as errors when computing the exit status.")
+				.display_order(41)
+		)
+		.arg(
+			Arg::with_name("Jobs")
+				.short("j")
+				.long("jobs")
+				.help("Parse up to N files at the same time. The output of each file is printed \
+					  in the order the files are given. Default: 1")
+				.takes_value(true)
+				.value_name("N")
+				.validator(|s| match s.parse::<usize>() {
+					Ok(n) if n > 0 => Ok(()),
+					_ => Err(String::from("N must be a positive integer")),
+				})
+				.display_order(42)
+		)
+		.arg(
+			Arg::with_name("Unordered")
+				.long("unordered")
+				.help("Print the output of each file as soon as it is parsed when using `--jobs`.")
+				.display_order(43)
+		)
+		.arg(
+			Arg::with_name("Output File")
+				.short("o")
+				.long("output")
+				.help("Write the output to FILE instead of stdout.")
+				.takes_value(true)
+				.value_name("FILE")
+				.display_order(44)
+		)
+		.arg(
+			Arg::with_name("Profile")
+				.long("profile")
+				.help("Use the defaults of the profile NAME in the configuration file.")
+				.takes_value(true)
+				.value_name("NAME")
+				.display_order(45)
+		)
+		.arg(
+			Arg::with_name("No Config")
+				.long("no-config")
+				.help("Ignore the configuration file and `XMLPARSE_OPTS`.")
+				.display_order(46)
 		)
 		.arg(
 			Arg::with_name("FILES")
-				.required_unless("Print Mappings")
-				.help("XML files to read")
+				.help("XML files to read. Reads stdin when no FILES are given or FILE is `-`. \
+					  Input compressed with gzip or xz is decompressed.")
 				.multiple(true)
-				.display_order(10)
+				.display_order(47)
+		)
+		.subcommand(
+			SubCommand::with_name("unflatten")
+				.about("Rebuilds XML from the line oriented output of xmlparse. The whitespace \
+					   options must match the ones used to produce the output. The output of a \
+					   single file without `-H` is expected, lines starting with a file name \
+					   are invalid.")
+				.args(&whitespace_args())
+				.args(&escape_args())
+				.arg(
+					Arg::with_name("FILES")
+						.help("Files containing xmlparse output. Reads stdin when no FILES \
+							  are given or FILE is `-`")
+						.multiple(true)
+						.display_order(11)
+				)
+		)
+		.subcommand(
+			SubCommand::with_name("unmap")
+				.about("Reverses the whitespace mapping and compression of the output of xmlparse. \
+					   The whitespace and escape options must match the ones used to produce \
+					   the output.")
+				.args(&whitespace_args())
+				.args(&escape_args())
+				.arg(
+					Arg::with_name("FILES")
+						.help("Files containing xmlparse output. Reads stdin when no FILES \
+							  are given or FILE is `-`")
+						.multiple(true)
+						.display_order(11)
+				)
 		)
 		.after_help(
 			"EXAMPLES: \n\
-			\tIf you want to keep visual whitespace while text processing; You can use sed to \n\
-			\tremove the visualizations as the last step of text processing. \n\
+			\tIf you want to keep visual whitespace while text processing; You can remove the \n\
+			\tvisualizations as the last step of text processing. \n\
+			\n\
+			\t$ xmlparse -m foo.xml | <Your text processing here> | xmlparse unmap -m \n\
+			\n\
+			\tThe output can be converted back to XML after processing it. \n\
+			\n\
+			\t$ xmlparse -m foo.xml | <Your text processing here> | xmlparse unflatten -m > bar.xml \n\
+			\n\
+			EXIT STATUS: \n\
+			\t0  All the documents were parsed successfully. \n\
+			\t1  A document contains errors, or warnings when `--strict` is given. The \n\
+			\t   errors the HTML parser recovers from count as warnings. \n\
+			\t2  Invalid usage or an I/O error. \n\
+			\n\
+			SECURITY: \n\
+			\tDocuments are parsed as untrusted. The network is never accessed, external DTDs \n\
+			\tare not loaded and entities are not expanded. Documents exceeding the limits of \n\
+			\t`--max-depth`, `--max-attributes` and `--max-text-size` fail with a fatal error. \n\
+			\tValidated documents load their DTD from the file system and expand the entities \n\
+			\tit declares, except for external general and parameter entities. The DTD and \n\
+			\tschemas of `--dtd`, `--xsd` and `--rng` only include files from the file system. \n\
 			\n\
-			\t$ MAPS=\"$(xmlparse  --print-mappings)\" \n\
-			\t$ xmlparse -m foo.xml | <Your text processing here> | sed \"y/$MAPS/ \\t\\n/\""
+			CONFIGURATION: \n\
+			\tThe defaults of the options are read from `$XDG_CONFIG_HOME/xmlparse/config.toml`, \n\
+			\tthen from the `XMLPARSE_OPTS` environment variable. The options given on the \n\
+			\tcommand line take precedence, `--include` and `--exclude` replace the patterns of \n\
+			\tthe defaults and a flag is turned off with `--no-FLAG`, e.g. `--no-map-whitespace`. \n\
+			\tThe keys of the file are the long names of the options, `false` turns a flag off \n\
+			\tand the options of a profile are in its `[profiles.NAME]` table. The options in \n\
+			\t`XMLPARSE_OPTS` are split on whitespace and can be quoted like in a shell. \n\
+			\n\
+			\tmap-whitespace = true \n\
+			\tcompress-level = 2 \n\
+			\n\
+			\t[profiles.diff] \n\
+			\tindices = \"always\" \n\
+			\tline-numbers = true"
 		)
 }
+
+// The flags that can be turned off again with `--no-FLAG`, when the defaults set them
+const NEGATABLE_FLAGS: &[(&str, &str)] = &[
+	("Map Whitespace", "no-map-whitespace"),
+	("Compress Whitespace", "no-compress-whitespace"),
+	("Escape Non ASCII", "no-escape-non-ascii"),
+	("HTML", "no-html"),
+	("Huge", "no-huge"),
+	("Validate", "no-validate"),
+	("Invalid Paths", "no-invalid-paths"),
+	("Keep All Whitespace", "no-keep-all-whitespace"),
+	("Merge CDATA", "no-merge-cdata"),
+	("Trim", "no-trim"),
+	("Normalize Space", "no-normalize-space"),
+	("Namespace Lines", "no-xmlns-lines"),
+	("Line Numbers", "no-line-numbers"),
+	("Byte Offsets", "no-byte-offset"),
+	("File Markers", "no-file-markers"),
+	("Quiet", "no-quiet"),
+	("Strict", "no-strict"),
+	("Unordered", "no-unordered"),
+];
+
+// Hidden from the help, which describes them once in the CONFIGURATION section
+fn negated_flags() -> Vec<Arg<'static, 'static>> {
+	NEGATABLE_FLAGS.iter()
+		.map(|&(name, long)| Arg::with_name(long).long(long).overrides_with(name).hidden(true))
+		.collect()
+}
+
+fn whitespace_args() -> Vec<Arg<'static, 'static>> {
+	vec![
+		Arg::with_name("Map Whitespace")
+			.short("m")
+			.long("map-whitespace")
+			.help("Transliterates whitespace characters to printable characters. Backslashes \
+				  and the characters whitespace is mapped to are escaped with a backslash \
+				  so that `unmap` can reverse the mapping.")
+			.display_order(2),
+		Arg::with_name("Whitespace Mapping")
+			.short("w")
+			.long("whitespace-map")
+			.help("Specifies the whitespace characters are mapped to. \
+				  The characters must be in the following order <SPACE><TAB><LF>. \
+				  Overrides: `--space-char`, `--tab-char`, `--newline-char`")
+			.takes_value(true)
+			.value_name("MAP")
+			.validator(|s| match s.chars().count() {
+				3 => Ok(()),
+				_ => Err(String::from("MAP must be three characters")),
+			})
+			.overrides_with_all(&["space-char", "tab-char", "newline-char"])
+			.display_order(3),
+		Arg::with_name("Space Character")
+			.long("space-char")
+			.help("Specifies the character <SPACE> is mapped to.")
+			.takes_value(true)
+			.value_name("CHAR")
+			.validator(single_char)
+			.display_order(4),
+		Arg::with_name("Tab Character")
+			.long("tab-char")
+			.help("Specifies the character <TAB> is mapped to.")
+			.takes_value(true)
+			.value_name("CHAR")
+			.validator(single_char)
+			.display_order(5),
+		Arg::with_name("Newline Character")
+			.long("newline-char")
+			.help("Specifies the character <LF> is mapped to.")
+			.takes_value(true)
+			.value_name("CHAR")
+			.validator(single_char)
+			.display_order(6),
+		Arg::with_name("Compress Whitespace")
+			.short("c")
+			.long("compress-whitespace")
+			.help("Compresses consecutive `space` characters to a `tab` character \
+				  according to the compression level. Tabs are printed as `\\t` and \
+				  backslashes are escaped with a backslash so that `unmap` can reverse the \
+				  compression.")
+			.display_order(7),
+		Arg::with_name("Compression Level")
+			.short("l")
+			.long("compress-level")
+			.help("Specifies the number consecutive spaces compressed to a \
+				  single character. Default: 4 spaces")
+			.takes_value(true)
+			.value_name("LEVEL")
+			.allow_hyphen_values(true)
+			.validator(|s| match s.parse::<usize>() {
+				Ok(n) if n > 0 => Ok(()),
+				_ => Err(String::from("LEVEL must be a positive integer")),
+			})
+			.display_order(8),
+	]
+}
+
+fn single_char(s: String) -> Result<(), String> {
+	match s.chars().count() {
+		1 => Ok(()),
+		_ => Err(String::from("CHAR must be a single character")),
+	}
+}
+
+fn escape_args() -> Vec<Arg<'static, 'static>> {
+	vec![
+		Arg::with_name("Escape Mode")
+			.short("e")
+			.long("escape")
+			.help("Escapes the characters of values that are part of the output grammar, \
+				  `\"`, `]`, `,`, `=`, tabs and newlines. `c` uses backslash escapes like `\\n` \
+				  and `xml` uses entities like `&#10;`. Whitespace mapped with `-m` is not escaped.")
+			.takes_value(true)
+			.value_name("MODE")
+			.possible_values(&["c", "xml"])
+			.hide_possible_values(true)
+			.display_order(9),
+		Arg::with_name("Escape Non ASCII")
+			.short("a")
+			.long("escape-non-ascii")
+			.help("Also escapes control and non-ASCII characters as `\\u{...}`, or `&#x...;` \
+				  with `--escape xml`. Implies `--escape c` when no mode is given.")
+			.display_order(10),
+	]
+}
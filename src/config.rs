@@ -16,8 +16,152 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use crate::filter::Pattern;
+
+use crate::schema::{ Dtd, RelaxNgSchema, XmlSchema };
+
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+	Xml,
+	/// Parsed leniently by libxml2's HTML parser, which adds implied tags such as
+	/// `html` and `body`
+	Html,
+}
+
+impl InputFormat {
+	/// Files ending with `.html` or `.htm` are HTML, every other file is XML. The
+	/// extension of compressed files, such as `.html.gz`, is ignored.
+	pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+		let mut path = path.as_ref();
+		if matches!(extension(path).as_deref(), Some("gz") | Some("xz")) {
+			path = Path::new(path.file_stem().unwrap_or_default());
+		}
+
+		match extension(path).as_deref() {
+			Some("html") | Some("htm") => InputFormat::Html,
+			_ => InputFormat::Xml,
+		}
+	}
+}
+
+/// How strings libxml2 reports that are not valid UTF-8 are handled
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+	/// Replace every invalid sequence with U+FFFD
+	Replace,
+	/// Replace every byte of an invalid sequence with `\xNN`
+	Escape,
+	/// Stop parsing the document with a fatal error
+	Fail,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+	/// `/path@[name=value,...]` and `/path="text"`
+	Path,
+	/// The format of the `xml2` tool, `/path/@name=value` and `/path=text`
+	Xml2,
+	/// A JSON object per line for every event
+	Jsonl,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EscapeMode {
+	None,
+	/// Backslash escapes, `\n`, `\"` and `\u{...}`
+	C,
+	/// Entities, `&#10;`, `&quot;` and `&#x...;`
+	Xml,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NameFormat {
+	/// Print names as they are written in the document, `prefix:local`
+	AsIs,
+	/// Strip the namespace prefix
+	Local,
+	/// Clark notation, `{uri}local`
+	Clark,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IndexMode {
+	Never,
+	/// Index every element, `/a[1]/b[1]`
+	Always,
+	/// Index the siblings after the first one with the same name, `/a/b`, `/a/b[2]`.
+	/// Whether an element is repeated is only known once its next sibling is parsed,
+	/// so the first one is never indexed.
+	Repeated,
+}
+
+fn extension(path: &Path) -> Option<String> {
+	path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TextNormalization {
+	None,
+	/// Strip the leading and trailing whitespace
+	Trim,
+	/// Trim and collapse the runs of whitespace to a single space like XPath's
+	/// `normalize-space()`
+	NormalizeSpace,
+}
+
 pub struct ProgramOpts {
+	/// Detected from the extension of each file when not set
+	pub input_format: Option<InputFormat>,
+	/// Overrides the encoding declared in the document or detected from its first bytes
+	pub encoding: Option<String>,
+	pub invalid_utf8: InvalidUtf8,
+
+	/// Lifts the limits libxml2 puts on the size of documents and entity expansion
+	pub huge: bool,
+	/// Documents exceeding a limit fail with a fatal error, `None` for no limit
+	pub max_depth: Option<usize>,
+	pub max_attributes: Option<usize>,
+	pub max_text_size: Option<usize>,
+
+	/// Check XML documents against the DTD they declare. The document is kept in
+	/// memory while it is parsed.
+	pub validate: bool,
+	/// Check XML documents against this DTD instead, implies `validate`
+	pub dtd: Option<Dtd>,
+	/// Check XML documents against this XML schema while they are parsed
+	pub xsd: Option<XmlSchema>,
+	/// Check XML documents against this RELAX NG schema while they are parsed. Only
+	/// the elements the schema cannot validate as a stream are kept in memory.
+	pub rng: Option<RelaxNgSchema>,
+	/// Print only the paths of the elements found invalid while parsing
+	pub invalid_paths: bool,
+
 	pub keep_all_whitespace: bool,
+	pub merge_cdata: bool,
+	/// Applied to every text node and attribute value
+	pub text_normalization: TextNormalization,
+	/// The text of a node is printed in parts once this many bytes are buffered, it is
+	/// printed as a whole when `None`
+	pub text_buffer_size: Option<usize>,
+
+	pub output_format: OutputFormat,
+	pub name_format: NameFormat,
+	pub namespace_lines: bool,
+	pub index_mode: IndexMode,
+
+	pub line_numbers: bool,
+	pub byte_offsets: bool,
+
+	/// Prefix every line with the name of the file followed by `filename_separator`
+	pub with_filename: bool,
+	pub filename_separator: String,
+	/// Print a line before and after the output of every file
+	pub file_markers: bool,
+
+	pub include: Vec<Pattern>,
+	pub exclude: Vec<Pattern>,
 
 	pub map_whitespace: bool,
 	pub tab_map: char,
@@ -26,12 +170,48 @@ pub struct ProgramOpts {
 
 	pub compress_whitespace: bool,
 	pub compress_level: usize,
+
+	pub escape_mode: EscapeMode,
+	pub escape_non_ascii: bool,
 }
 
 impl Default for ProgramOpts {
 	fn default() -> Self {
 		ProgramOpts {
+			input_format: None,
+			encoding: None,
+			invalid_utf8: InvalidUtf8::Replace,
+
+			huge: false,
+			max_depth: Some(1024),
+			max_attributes: Some(256),
+			max_text_size: Some(10_000_000),
+
+			validate: false,
+			dtd: None,
+			xsd: None,
+			rng: None,
+			invalid_paths: false,
+
 			keep_all_whitespace: false,
+			merge_cdata: false,
+			text_normalization: TextNormalization::None,
+			text_buffer_size: None,
+
+			output_format: OutputFormat::Path,
+			name_format: NameFormat::AsIs,
+			namespace_lines: false,
+			index_mode: IndexMode::Never,
+
+			line_numbers: false,
+			byte_offsets: false,
+
+			with_filename: false,
+			filename_separator: String::from(":"),
+			file_markers: false,
+
+			include: Vec::new(),
+			exclude: Vec::new(),
 
 			map_whitespace: false,
 			tab_map: '»',
@@ -40,6 +220,37 @@ impl Default for ProgramOpts {
 
 			compress_whitespace: false,
 			compress_level: 4,
+
+			escape_mode: EscapeMode::None,
+			escape_non_ascii: false,
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::InputFormat;
+
+	fn is_html(path: &str) -> bool {
+		InputFormat::from_path(path) == InputFormat::Html
+	}
+
+	#[test]
+	fn format_from_extension() {
+		assert!(is_html("a.html"));
+		assert!(is_html("dir/a.HTM"));
+		assert!(!is_html("a.xml"));
+		assert!(!is_html("html"));
+		assert!(!is_html("a.html/b"));
+	}
+
+	#[test]
+	fn compressed_extension_stripped() {
+		assert!(is_html("a.html.gz"));
+		assert!(is_html("dir/a.htm.xz"));
+		assert!(!is_html("a.xml.gz"));
+		assert!(!is_html("a.gz"));
+		assert!(!is_html("html.xz"));
+		assert!(is_html("a.gz.html.gz"));
+	}
+}
@@ -0,0 +1,169 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{ self, Cursor, ErrorKind, Read };
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Wraps the reader in a decoder when the stream starts with the magic bytes of gzip or
+/// xz. Every other stream is read as is.
+pub fn decompress<'a, R: Read + 'a>(mut reader: R) -> io::Result<Box<dyn Read + 'a>> {
+	// A pipe might return less than the magic bytes in a single read
+	let mut magic = Vec::with_capacity(XZ_MAGIC.len());
+	while magic.len() < XZ_MAGIC.len() {
+		let mut buf = [0; XZ_MAGIC.len()];
+		let len = XZ_MAGIC.len() - magic.len();
+		match reader.read(&mut buf[..len]) {
+			Ok(0) => break,
+			Ok(n) => magic.extend_from_slice(&buf[..n]),
+			Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		}
+	}
+
+	let is_gzip = magic.starts_with(GZIP_MAGIC);
+	let is_xz = magic.starts_with(XZ_MAGIC);
+
+	// The magic bytes are part of the compressed stream
+	let reader = Cursor::new(magic).chain(reader);
+	if is_gzip {
+		gzip_decoder(reader)
+	} else if is_xz {
+		xz_decoder(reader)
+	} else {
+		Ok(Box::new(reader))
+	}
+}
+
+// Files compressed by concatenating several gzip or xz streams are read to the end
+#[cfg(feature="gzip")]
+fn gzip_decoder<'a, R: Read + 'a>(reader: R) -> io::Result<Box<dyn Read + 'a>> {
+	Ok(Box::new(flate2::read::MultiGzDecoder::new(reader)))
+}
+
+#[cfg(not(feature="gzip"))]
+fn gzip_decoder<'a, R: Read + 'a>(_reader: R) -> io::Result<Box<dyn Read + 'a>> {
+	Err(unsupported("gzip"))
+}
+
+#[cfg(feature="xz")]
+fn xz_decoder<'a, R: Read + 'a>(reader: R) -> io::Result<Box<dyn Read + 'a>> {
+	Ok(Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)))
+}
+
+#[cfg(not(feature="xz"))]
+fn xz_decoder<'a, R: Read + 'a>(_reader: R) -> io::Result<Box<dyn Read + 'a>> {
+	Err(unsupported("xz"))
+}
+
+#[cfg(not(all(feature="gzip", feature="xz")))]
+fn unsupported(format: &str) -> io::Error {
+	io::Error::new(ErrorKind::Other, format!("The input is {} compressed, but xmlparse was built without the `{}` feature", format, format))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::decompress;
+
+	use std::io::{ self, Read };
+
+	const XML: &[u8] = b"<a>compressed</a>\n";
+
+	// Returns a single byte per read like a slow pipe
+	struct Trickle<'a>(&'a [u8]);
+
+	impl Read for Trickle<'_> {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			match (buf.first_mut(), self.0.split_first()) {
+				(Some(out), Some((&byte, rest))) => {
+					*out = byte;
+					self.0 = rest;
+					Ok(1)
+				},
+				_ => Ok(0),
+			}
+		}
+	}
+
+	fn read_all(input: &[u8]) -> io::Result<Vec<u8>> {
+		let mut out = Vec::new();
+		decompress(Trickle(input))?.read_to_end(&mut out)?;
+		Ok(out)
+	}
+
+	#[test]
+	fn plain_input() {
+		assert_eq!(read_all(XML).unwrap(), XML);
+		assert_eq!(read_all(b"<").unwrap(), b"<");
+		assert_eq!(read_all(b"").unwrap(), b"");
+		assert_eq!(read_all(&[0x1f]).unwrap(), [0x1f]);
+	}
+
+	#[cfg(feature="gzip")]
+	fn gzip(data: &[u8]) -> Vec<u8> {
+		use std::io::Write;
+
+		let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+		encoder.write_all(data).unwrap();
+		encoder.finish().unwrap()
+	}
+
+	#[cfg(feature="gzip")]
+	#[test]
+	fn gzip_input() {
+		assert_eq!(read_all(&gzip(XML)).unwrap(), XML);
+
+		let mut members = gzip(b"<a>first");
+		members.extend(gzip(b" second</a>"));
+		assert_eq!(read_all(&members).unwrap(), b"<a>first second</a>");
+	}
+
+	#[cfg(feature="xz")]
+	fn xz(data: &[u8]) -> Vec<u8> {
+		use std::io::Write;
+
+		let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+		encoder.write_all(data).unwrap();
+		encoder.finish().unwrap()
+	}
+
+	#[cfg(feature="xz")]
+	#[test]
+	fn xz_input() {
+		assert_eq!(read_all(&xz(XML)).unwrap(), XML);
+
+		let mut streams = xz(b"<a>first");
+		streams.extend(xz(b" second</a>"));
+		assert_eq!(read_all(&streams).unwrap(), b"<a>first second</a>");
+	}
+
+	#[cfg(not(feature="gzip"))]
+	#[test]
+	fn gzip_unsupported() {
+		let e = read_all(&[0x1f, 0x8b, 0x08, 0x00]).unwrap_err();
+		assert!(e.to_string().contains("`gzip` feature"));
+	}
+
+	#[cfg(not(feature="xz"))]
+	#[test]
+	fn xz_unsupported() {
+		let e = read_all(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]).unwrap_err();
+		assert!(e.to_string().contains("`xz` feature"));
+	}
+}
@@ -0,0 +1,244 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// The defaults of the command line options are read from a TOML file whose keys are
+// the long names of the options:
+//
+//     map-whitespace = true
+//     compress-level = 2
+//     include = [ "/root/**" ]
+//
+//     [profiles.diff]
+//     indices = "always"
+//
+// The options are turned into arguments placed before the ones given on the command
+// line, which take precedence since every option overrides its earlier occurrences.
+// Flags are turned off with their `--no-` flag, which `false` is turned into, and the
+// patterns of `--include` and `--exclude` given on the command line replace the ones
+// of the defaults.
+
+use toml::Value;
+use toml::value::Table;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+pub const ENV_VAR: &str = "XMLPARSE_OPTS";
+
+const PROFILES_KEY: &str = "profiles";
+
+pub struct Config {
+	path: PathBuf,
+	table: Table,
+}
+
+// `$XDG_CONFIG_HOME/xmlparse/config.toml`, or `~/.config/xmlparse/config.toml`
+pub fn config_path() -> Option<PathBuf> {
+	let config_dir = env::var_os("XDG_CONFIG_HOME")
+		.filter(|dir| !dir.is_empty())
+		.map(PathBuf::from)
+		.or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+	Some(config_dir.join("xmlparse").join("config.toml"))
+}
+
+// A missing file is the same as an empty one
+pub fn load_config(path: PathBuf) -> Result<Option<Config>, String> {
+	let contents = match fs::read_to_string(&path) {
+		Ok(contents) => contents,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(format!("{}: {}", path.display(), e)),
+	};
+
+	Config::parse(path, &contents).map(Some)
+}
+
+impl Config {
+	pub fn parse(path: PathBuf, contents: &str) -> Result<Self, String> {
+		match contents.parse::<Value>() {
+			Ok(Value::Table(table)) => Ok(Config { path, table }),
+			Ok(_) => Err(format!("{}: Expected a table", path.display())),
+			Err(e) => Err(format!("{}: {}", path.display(), e)),
+		}
+	}
+
+	pub fn path(&self) -> &PathBuf {
+		&self.path
+	}
+
+	// The top level options followed by the options of the profile
+	pub fn args(&self, profile: Option<&str>) -> Result<Vec<String>, String> {
+		let mut args = Vec::new();
+		table_to_args(&self.table, &mut args).map_err(|e| format!("{}: {}", self.path.display(), e))?;
+
+		if let Some(profile) = profile {
+			let table = self.table.get(PROFILES_KEY)
+				.and_then(|profiles| profiles.get(profile))
+				.and_then(|profile| profile.as_table())
+				.ok_or_else(|| format!("{}: Profile `{}` not found", self.path.display(), profile))?;
+
+			table_to_args(table, &mut args)
+				.map_err(|e| format!("{}: Profile `{}`: {}", self.path.display(), profile, e))?;
+		}
+
+		Ok(args)
+	}
+}
+
+// The options in `XMLPARSE_OPTS` are separated by whitespace and quoted like in a shell
+pub fn env_args() -> Result<Vec<String>, String> {
+	match env::var(ENV_VAR) {
+		Ok(opts) => split_args(&opts),
+		Err(_) => Ok(Vec::new()),
+	}
+}
+
+// Single quotes keep everything up to the next one as it is. Backslashes escape the next
+// character outside of quotes, and `"` and `\` inside double quotes.
+fn split_args(string: &str) -> Result<Vec<String>, String> {
+	let mut args = Vec::new();
+	let mut arg: Option<String> = None;
+	let mut chars = string.chars();
+
+	while let Some(c) = chars.next() {
+		match c {
+			c if c.is_whitespace() => args.extend(arg.take()),
+			'\\' => arg.get_or_insert_with(String::new).extend(chars.next()),
+			'\'' => {
+				let arg = arg.get_or_insert_with(String::new);
+				loop {
+					match chars.next() {
+						Some('\'') => break,
+						Some(c) => arg.push(c),
+						None => return Err(String::from("Unterminated single quote")),
+					}
+				}
+			},
+			'"' => {
+				let arg = arg.get_or_insert_with(String::new);
+				loop {
+					match chars.next() {
+						Some('"') => break,
+						Some('\\') => match chars.next() {
+							Some(c @ ('"' | '\\')) => arg.push(c),
+							Some(c) => arg.extend(['\\', c]),
+							None => return Err(String::from("Unterminated double quote")),
+						},
+						Some(c) => arg.push(c),
+						None => return Err(String::from("Unterminated double quote")),
+					}
+				}
+			},
+			c => arg.get_or_insert_with(String::new).push(c),
+		}
+	}
+
+	args.extend(arg);
+	Ok(args)
+}
+
+fn table_to_args(table: &Table, args: &mut Vec<String>) -> Result<(), String> {
+	for (key, value) in table {
+		match value {
+			Value::Table(_) if key == PROFILES_KEY => continue,
+			Value::Boolean(true) => args.push(format!("--{}", key)),
+			Value::Boolean(false) => args.push(format!("--no-{}", key)),
+			Value::Array(values) => {
+				for value in values {
+					args.push(format!("--{}={}", key, scalar_to_string(key, value)?));
+				}
+			},
+			value => args.push(format!("--{}={}", key, scalar_to_string(key, value)?)),
+		}
+	}
+
+	Ok(())
+}
+
+fn scalar_to_string(key: &str, value: &Value) -> Result<String, String> {
+	match value {
+		Value::String(string) => Ok(string.clone()),
+		Value::Integer(n) => Ok(n.to_string()),
+		_ => Err(format!("`{}` must be a boolean, a string, an integer or an array of them", key)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ split_args, table_to_args, Config, Table, Value };
+
+	use std::path::PathBuf;
+
+	fn config(contents: &str) -> Config {
+		Config::parse(PathBuf::from("config.toml"), contents).unwrap()
+	}
+
+	fn args(contents: &str) -> Result<Vec<String>, String> {
+		let table: Table = contents.parse::<Value>().unwrap().as_table().unwrap().clone();
+		let mut args = Vec::new();
+		table_to_args(&table, &mut args).map(|_| args)
+	}
+
+	#[test]
+	fn scalar_values() {
+		assert_eq!(args("format = \"jsonl\"\njobs = 10").unwrap(), ["--format=jsonl", "--jobs=10"]);
+		assert_eq!(args("filename-separator = \" \"").unwrap(), ["--filename-separator= "]);
+	}
+
+	#[test]
+	fn array_values() {
+		assert_eq!(args("include = [\"a\", \"/b/*\"]").unwrap(), ["--include=a", "--include=/b/*"]);
+		assert_eq!(args("include = []").unwrap(), Vec::<String>::new());
+	}
+
+	#[test]
+	fn boolean_values() {
+		assert_eq!(args("map-whitespace = true\nstrict = false").unwrap(), ["--map-whitespace", "--no-strict"]);
+	}
+
+	#[test]
+	fn invalid_values() {
+		assert!(args("jobs = 1.5").is_err());
+		assert!(args("include = [[\"a\"]]").is_err());
+		assert!(args("line-numbers = { a = 1 }").is_err());
+	}
+
+	#[test]
+	fn profiles() {
+		let config = config("strict = true\n\n[profiles.diff]\nstrict = false\nindices = \"always\"");
+		assert_eq!(config.args(None).unwrap(), ["--strict"]);
+		assert_eq!(config.args(Some("diff")).unwrap(), ["--strict", "--indices=always", "--no-strict"]);
+		assert!(config.args(Some("missing")).is_err());
+	}
+
+	#[test]
+	fn split_whitespace() {
+		assert_eq!(split_args("  -m\t--strict \n").unwrap(), ["-m", "--strict"]);
+		assert_eq!(split_args("").unwrap(), Vec::<String>::new());
+	}
+
+	#[test]
+	fn split_quoted() {
+		assert_eq!(split_args("--filename-separator ' '").unwrap(), ["--filename-separator", " "]);
+		assert_eq!(split_args("--include=\"/a b/*\"").unwrap(), ["--include=/a b/*"]);
+		assert_eq!(split_args("'a\\b' \"c\\\"d\\e\" f\\ g ''").unwrap(), ["a\\b", "c\"d\\e", "f g", ""]);
+		assert!(split_args("'a").is_err());
+		assert!(split_args("\"a").is_err());
+	}
+}
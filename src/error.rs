@@ -0,0 +1,100 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::config::InputFormat;
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+	Warning,
+	Error,
+	Fatal,
+}
+
+/// A warning or error reported by libxml2 while parsing a document.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+	pub severity: Severity,
+	pub message: String,
+	/// The position in the document the diagnostic refers to, if known
+	pub line: Option<u32>,
+	pub column: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+	/// Reading the input or writing the output failed.
+	Io(std::io::Error),
+	/// The document contains errors. Holds every diagnostic reported for it,
+	/// including warnings.
+	Parse(Vec<Diagnostic>),
+}
+
+// Successful parses hold the warnings reported for the document
+pub type ParseResult = Result<Vec<Diagnostic>, Error>;
+
+// The HTML parser recovers from every error that isn't fatal
+pub(crate) fn into_parse_result(diagnostics: Vec<Diagnostic>, format: InputFormat) -> ParseResult {
+	let failure = match format {
+		InputFormat::Xml => Severity::Error,
+		InputFormat::Html => Severity::Fatal,
+	};
+
+	if diagnostics.iter().any(|d| d.severity >= failure) {
+		Err(Error::Parse(diagnostics))
+	} else {
+		Ok(diagnostics)
+	}
+}
+
+impl fmt::Display for Severity {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Severity::Warning => write!(f, "Warning!"),
+			Severity::Error => write!(f, "Error!"),
+			Severity::Fatal => write!(f, "Fatal Error!"),
+		}
+	}
+}
+
+impl fmt::Display for Diagnostic {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}: {}", self.severity, self.message)
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::Io(e) => write!(f, "{}", e),
+			Error::Parse(diagnostics) => {
+				let errors = diagnostics.iter().filter(|d| d.severity >= Severity::Error).count();
+				write!(f, "The document contains {} error(s)", errors)
+			},
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+	fn from(e: std::io::Error) -> Self {
+		Error::Io(e)
+	}
+}
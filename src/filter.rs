@@ -0,0 +1,170 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+/// A glob-style pattern matched against the path of an element.
+///
+/// Segments are separated by `/`. `*` matches any number of characters in a name,
+/// `?` a single character and `**` any number of segments. Patterns that do not
+/// start with `/` match at any depth.
+pub struct Pattern {
+	segments: Vec<Segment>,
+}
+
+enum Segment {
+	AnyDepth,
+	Name(Vec<char>),
+}
+
+// Whether an element and the events inside it are printed
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+	Visible,
+	// Not printed, but its descendants might be included
+	Hidden,
+	// Neither the element nor its descendants are printed
+	Excluded,
+}
+
+impl Pattern {
+	pub fn new(pattern: &str) -> Self {
+		let mut segments = Vec::new();
+		if !pattern.starts_with('/') {
+			segments.push(Segment::AnyDepth);
+		}
+
+		segments.extend(pattern.split('/').filter(|s| !s.is_empty()).map(|s| match s {
+			"**" => Segment::AnyDepth,
+			_ => Segment::Name(s.chars().collect()),
+		}));
+
+		Pattern { segments }
+	}
+
+	pub fn matches(&self, path: &[&str]) -> bool {
+		match_segments(&self.segments, path)
+	}
+}
+
+fn match_segments(segments: &[Segment], path: &[&str]) -> bool {
+	match segments.split_first() {
+		None => path.is_empty(),
+		Some((Segment::AnyDepth, rest)) => (0..=path.len()).any(|i| match_segments(rest, &path[i..])),
+		Some((Segment::Name(glob), rest)) => {
+			!path.is_empty() && match_glob(glob, path[0]) && match_segments(rest, &path[1..])
+		},
+	}
+}
+
+// Matches a name against `*` and `?` wildcards. On a mismatch the last `*` is
+// retried one character further, which is enough since a later `*` can match
+// anything an earlier one can.
+fn match_glob(glob: &[char], name: &str) -> bool {
+	let name: Vec<char> = name.chars().collect();
+	let (mut g, mut n) = (0, 0);
+	let mut backtrack = None;
+
+	while n < name.len() {
+		match glob.get(g) {
+			Some('*') => {
+				backtrack = Some((g, n));
+				g += 1;
+			},
+			Some(&c) if c == '?' || c == name[n] => {
+				g += 1;
+				n += 1;
+			},
+			_ => match backtrack {
+				Some((star, pos)) => {
+					backtrack = Some((star, pos + 1));
+					g = star + 1;
+					n = pos + 1;
+				},
+				None => return false,
+			},
+		}
+	}
+
+	glob[g..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ match_glob, Pattern };
+
+	fn glob(glob: &str, name: &str) -> bool {
+		match_glob(&glob.chars().collect::<Vec<_>>(), name)
+	}
+
+	fn matches(pattern: &str, path: &str) -> bool {
+		let path: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+		Pattern::new(pattern).matches(&path)
+	}
+
+	#[test]
+	fn glob_wildcards() {
+		assert!(glob("*", ""));
+		assert!(glob("*", "item"));
+		assert!(glob("it*", "item"));
+		assert!(glob("*em", "item"));
+		assert!(glob("i?e?", "item"));
+		assert!(glob("**", "item"));
+		assert!(!glob("?", ""));
+		assert!(!glob("i?em", "iem"));
+		assert!(!glob("item", "items"));
+		assert!(!glob("*x", "item"));
+	}
+
+	#[test]
+	fn glob_backtracking() {
+		assert!(glob("*a*b", "aaab"));
+		assert!(glob("*ab", "aabab"));
+		assert!(glob("a*b*c", "abbbc"));
+		assert!(glob("*é", "café"));
+		assert!(!glob("*a*b", "aaba"));
+		assert!(!glob("a*b*c", "abcb"));
+	}
+
+	#[test]
+	fn absolute_patterns() {
+		assert!(matches("/a/b", "/a/b"));
+		assert!(matches("/a/*", "/a/b"));
+		assert!(!matches("/a/b", "/a/b/c"));
+		assert!(!matches("/b", "/a/b"));
+		assert!(!matches("/a/*", "/a"));
+	}
+
+	#[test]
+	fn relative_patterns() {
+		assert!(matches("b", "/b"));
+		assert!(matches("b", "/a/b"));
+		assert!(matches("a/b", "/x/a/b"));
+		assert!(!matches("b", "/a/b/c"));
+		assert!(!matches("a/b", "/a/x/b"));
+	}
+
+	#[test]
+	fn any_depth() {
+		assert!(matches("/a/**/c", "/a/c"));
+		assert!(matches("/a/**/c", "/a/b/b/c"));
+		assert!(matches("/a/**", "/a"));
+		assert!(matches("/a/**", "/a/b/c"));
+		assert!(matches("/**/b/**/d", "/a/b/c/d"));
+		assert!(!matches("/a/**/c", "/a/b/d"));
+		assert!(!matches("/a/**/c", "/b/c"));
+	}
+}
@@ -0,0 +1,96 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::locator::Locator;
+
+use std::fmt;
+
+/// The name of an element or attribute.
+#[derive(Clone, Copy)]
+pub struct QName<'a> {
+	pub local_name: &'a str,
+	pub prefix: Option<&'a str>,
+	/// The namespace the name belongs to, `None` if it is not in a namespace.
+	pub uri: Option<&'a str>,
+}
+
+/// An attribute of an element as reported to [`XmlEventHandler::start_element`].
+pub struct Attribute<'a> {
+	pub name: QName<'a>,
+	pub value: &'a str,
+}
+
+/// A namespace declared on an element with `xmlns` or `xmlns:prefix`.
+pub struct Namespace<'a> {
+	/// `None` for the default namespace.
+	pub prefix: Option<&'a str>,
+	pub uri: &'a str,
+}
+
+/// Receives the events produced while parsing a document.
+///
+/// The strings passed to the handler are borrowed from libxml2 and are only valid
+/// for the duration of the call. Every method has an empty default implementation
+/// so handlers only need to implement the events they are interested in.
+pub trait XmlEventHandler {
+	/// Called before every other event with the position of the parser, which is
+	/// right after the construct the event is about.
+	fn location(&mut self, _locator: &Locator) {}
+
+	/// Called for the start tag of an element. `namespaces` holds the namespaces
+	/// declared on it, which are not included in `attrs`.
+	fn start_element(&mut self, _name: &QName, _attrs: &[Attribute], _namespaces: &[Namespace]) {}
+
+	fn end_element(&mut self, _name: &QName) {}
+
+	/// Called for text content, including whitespace between tags.
+	fn characters(&mut self, _chars: &str) {}
+
+	/// Called for the content of CDATA sections. Treated as text by default.
+	fn cdata(&mut self, data: &str) {
+		self.characters(data);
+	}
+
+	fn comment(&mut self, _comment: &str) {}
+
+	fn processing_instruction(&mut self, _target: &str, _data: &str) {}
+
+	/// Called when the current element is found invalid while the document is
+	/// validated. The error itself is reported with the other errors of the document.
+	fn invalid(&mut self, _message: &str) {}
+}
+
+// Formats the name as it was written in the document
+impl<'a> fmt::Display for QName<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.prefix {
+			Some(prefix) => write!(f, "{}:{}", prefix, self.local_name),
+			None => write!(f, "{}", self.local_name),
+		}
+	}
+}
+
+// Formats the attribute name used to declare the namespace
+impl<'a> fmt::Display for Namespace<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.prefix {
+			Some(prefix) => write!(f, "xmlns:{}", prefix),
+			None => write!(f, "xmlns"),
+		}
+	}
+}
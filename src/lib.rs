@@ -35,45 +35,161 @@ mod bindings {
 mod ptr_conversions {
 	use crate::bindings::xmlChar;
 
-	pub fn str_from_xmlchar_with_null<'a>(chars: *const xmlChar) -> &'a str {
-		unsafe {
-			let chars = std::ffi::CStr::from_ptr(chars as *const i8).to_bytes();
-			std::str::from_utf8_unchecked(chars)
-		}
+	// The strings libxml2 passes to the callbacks are UTF-8 but are not guaranteed to
+	// be valid, they are checked by `sax.rs` before they are used as `str`
+	pub fn bytes_from_xmlchar_with_null<'a>(chars: *const xmlChar) -> &'a [u8] {
+		unsafe { std::ffi::CStr::from_ptr(chars as *const i8).to_bytes() }
 	}
 
-	pub fn str_from_xmlchar<'a>(chars: *const xmlChar, len: isize) -> &'a str {
-		unsafe {
-			let chars = std::slice::from_raw_parts(chars, len as usize);
-			std::str::from_utf8_unchecked(chars)
+	pub fn bytes_from_xmlchar<'a>(chars: *const xmlChar, len: isize) -> &'a [u8] {
+		if chars.is_null() || len <= 0 {
+			return &[];
 		}
+
+		unsafe { std::slice::from_raw_parts(chars, len as usize) }
 	}
 
-	pub fn slice_from_ptr_with_null<'a>(ptr: *mut *const xmlChar) -> &'a [*const xmlChar] {
-		unsafe {
-			let mut len = 0;
-			while !(*ptr.add(len)).is_null() { len += 1; }
+	pub fn option_bytes_from_xmlchar_with_null<'a>(chars: *const xmlChar) -> Option<&'a [u8]> {
+		if chars.is_null() {
+			None
+		} else {
+			Some(bytes_from_xmlchar_with_null(chars))
+		}
+	}
 
-			std::slice::from_raw_parts(ptr, len)
+	pub fn slice_from_ptr<'a>(ptr: *mut *const xmlChar, len: usize) -> &'a [*const xmlChar] {
+		if ptr.is_null() {
+			return &[];
 		}
+
+		unsafe { std::slice::from_raw_parts(ptr, len) }
 	}
 }
 
 mod sax;
 
+mod handler;
+
+mod printer;
+
 mod parser_data;
 
 mod config;
 
-pub use config::ProgramOpts;
+mod unflatten;
+
+mod unmap;
+
+mod error;
+
+mod locator;
+
+mod filter;
+
+mod decompress;
+
+mod schema;
 
-pub fn print_nodes(file: String, opts: &ProgramOpts) {
-	let file = std::ffi::CString::new(file).unwrap();
+pub use config::{ EscapeMode, IndexMode, InputFormat, InvalidUtf8, NameFormat, OutputFormat, ProgramOpts, TextNormalization };
+
+pub use handler::{ Attribute, Namespace, QName, XmlEventHandler };
+
+pub use error::{ Diagnostic, Error, ParseResult, Severity };
+
+pub use locator::Locator;
+
+pub use filter::Pattern;
+
+pub use schema::{ Dtd, RelaxNgSchema, XmlSchema };
+
+use std::io::{ BufWriter, Read, Write };
+
+pub use unflatten::unflatten;
+
+pub use unmap::unmap;
+
+/// Initializes the global state of libxml2. It must be called once, before documents are
+/// parsed on more than one thread.
+pub fn init_parser() {
+	unsafe { bindings::xmlInitParser(); }
+}
+
+pub fn print_nodes(file: String, opts: &ProgramOpts) -> ParseResult {
+	let stdout = std::io::stdout();
+	write_nodes(file, &mut stdout.lock(), opts)
+}
+
+pub fn write_nodes<W: Write>(file: String, out: &mut W, opts: &ProgramOpts) -> ParseResult {
+	let name = if file == "-" { String::from("<stdin>") } else { file.clone() };
+
+	let mut data = parser_data::ParserData::with_capacity(10, opts, BufWriter::new(out));
+	if opts.with_filename {
+		data.set_filename(Some(name.clone()));
+	}
+
+	if opts.file_markers {
+		data.print_file_start(&name)?;
+	}
+
+	let result = parse_file_with_opts(file, opts, &mut data);
+	data.flush_text();
+
+	// The end marker is printed even if the document is malformed
+	if opts.file_markers {
+		data.try_print(|data| data.print_file_end(&name));
+	}
+
+	// A failed write takes precedence over the errors in the document
+	data.finish()?.flush()?;
+	result
+}
+
+/// Parses the file as HTML if its extension is `.html` or `.htm`, and as XML otherwise
+pub fn parse_file_with<H: XmlEventHandler>(file: String, handler: &mut H) -> ParseResult {
+	parse_file_with_opts(file, &ProgramOpts::default(), handler)
+}
+
+pub fn parse_file_as<H: XmlEventHandler>(file: String, format: InputFormat, handler: &mut H) -> ParseResult {
+	let opts = ProgramOpts { input_format: Some(format), ..ProgramOpts::default() };
+	parse_file_with_opts(file, &opts, handler)
+}
+
+/// Parses the file with the input options of `opts`, its format is detected from the
+/// extension unless `input_format` is set
+pub fn parse_file_with_opts<H: XmlEventHandler>(file: String, opts: &ProgramOpts, handler: &mut H) -> ParseResult {
+	let format = opts.input_format.unwrap_or_else(|| InputFormat::from_path(&file));
+	if file == "-" {
+		let stdin = std::io::stdin();
+		return parse_reader_with_opts(stdin.lock(), opts, handler);
+	}
+
+	let mut reader = decompress::decompress(std::fs::File::open(&file)?)?;
+	let file = std::ffi::CString::new(file).map_err(std::io::Error::from)?;
+
+	let mut sax = sax::default_sax_handler();
+	sax::init_sax_handler(&mut sax, format, opts);
+
+	let diagnostics = sax::sax_user_parse_reader(&mut sax, format, opts, handler, &mut reader, Some(&file))?;
+	error::into_parse_result(diagnostics, format)
+}
+
+pub fn parse_reader_with<R: Read, H: XmlEventHandler>(reader: R, handler: &mut H) -> ParseResult {
+	parse_reader_as(reader, InputFormat::Xml, handler)
+}
+
+pub fn parse_reader_as<R: Read, H: XmlEventHandler>(reader: R, format: InputFormat, handler: &mut H) -> ParseResult {
+	let opts = ProgramOpts { input_format: Some(format), ..ProgramOpts::default() };
+	parse_reader_with_opts(reader, &opts, handler)
+}
 
-	let mut handler = sax::default_sax_handler();
-	sax::init_sax_handler(&mut handler);
+/// Parses the reader with the input options of `opts`, as XML unless `input_format`
+/// is set
+pub fn parse_reader_with_opts<R: Read, H: XmlEventHandler>(reader: R, opts: &ProgramOpts, handler: &mut H) -> ParseResult {
+	let format = opts.input_format.unwrap_or(InputFormat::Xml);
+	let mut reader = decompress::decompress(reader)?;
+	let mut sax = sax::default_sax_handler();
+	sax::init_sax_handler(&mut sax, format, opts);
 
-	let mut data = parser_data::ParserData::with_capacity(10, opts);
-	let data_ptr = &mut data as *mut _ as *mut cty::c_void;
-	sax::sax_user_parse_file(&mut handler, data_ptr, file);
+	let diagnostics = sax::sax_user_parse_reader(&mut sax, format, opts, handler, &mut reader, None)?;
+	error::into_parse_result(diagnostics, format)
 }
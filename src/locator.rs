@@ -0,0 +1,53 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bindings::{ self, xmlParserCtxtPtr };
+
+use std::marker::PhantomData;
+
+use cty::c_void;
+
+/// Gives access to the position of the parser in the document. Passed to
+/// [`XmlEventHandler::location`](crate::XmlEventHandler::location) and only valid
+/// for the duration of the call.
+pub struct Locator<'a> {
+	ctxt: xmlParserCtxtPtr,
+	marker: PhantomData<&'a ()>,
+}
+
+impl<'a> Locator<'a> {
+	pub(crate) fn new(ctxt: xmlParserCtxtPtr) -> Self {
+		Locator { ctxt, marker: PhantomData }
+	}
+
+	/// The current line, starting from 1.
+	pub fn line(&self) -> u32 {
+		unsafe { bindings::xmlSAX2GetLineNumber(self.ctxt as *mut c_void) as u32 }
+	}
+
+	/// The current column, starting from 1.
+	pub fn column(&self) -> u32 {
+		unsafe { bindings::xmlSAX2GetColumnNumber(self.ctxt as *mut c_void) as u32 }
+	}
+
+	/// The number of bytes of the input consumed so far. Computing it is expensive
+	/// for documents that are not encoded in UTF-8.
+	pub fn byte_offset(&self) -> u64 {
+		unsafe { bindings::xmlByteConsumed(self.ctxt) as u64 }
+	}
+}
@@ -18,6 +18,24 @@
 
 mod cli;
 
+mod defaults;
+
+use clap::{ ArgMatches, ErrorKind };
+
+use xmlparse::Diagnostic;
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{ stdin, stdout, BufRead, BufReader, BufWriter, StdoutLock, Write };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::sync::mpsc;
+use std::thread;
+
+// Exit statuses
+const EXIT_PARSE_ERROR: i32 = 1;
+const EXIT_FAILURE: i32 = 2;
+
 fn main() {
 	let exit_code = real_main();
 	std::process::exit(exit_code);
@@ -26,9 +44,387 @@ fn main() {
 fn real_main() -> i32 {
 	let mut opts = xmlparse::ProgramOpts::default();
 
-	let app = cli::build_cli();
-	let matches = app.get_matches();
+	let (matches, cli_matches) = match get_matches() {
+		Ok(matches) => matches,
+		Err(exit_code) => return exit_code,
+	};
+
+	// The defaults are given before the subcommand
+	if let (subcommand, Some(sub_matches)) = matches.subcommand() {
+		parse_value_opts(&matches, &mut opts);
+		parse_value_opts(sub_matches, &mut opts);
+		return match subcommand {
+			"unmap" => convert_files(sub_matches, &opts, |input, output, opts| xmlparse::unmap(input, output, opts)),
+			_ => convert_files(sub_matches, &opts, |input, output, opts| xmlparse::unflatten(input, output, opts)),
+		};
+	}
+
+	parse_value_opts(&matches, &mut opts);
+
+	if matches.is_present("Print Mappings") {
+		println!("{}{}{}", opts.space_map, opts.tab_map, opts.newline_map);
+		return 0;
+	}
+
+	if matches.is_present("HTML") {
+		opts.input_format = Some(xmlparse::InputFormat::Html);
+	}
+
+	opts.encoding = matches.value_of("Encoding").map(str::to_owned);
+	opts.invalid_utf8 = match matches.value_of("Invalid UTF-8") {
+		Some("escape") => xmlparse::InvalidUtf8::Escape,
+		Some("fail") => xmlparse::InvalidUtf8::Fail,
+		_ => xmlparse::InvalidUtf8::Replace,
+	};
+
+	// Only the limits given explicitly apply to huge documents
+	if matches.is_present("Huge") {
+		opts.huge = true;
+		opts.max_depth = None;
+		opts.max_attributes = None;
+		opts.max_text_size = None;
+	}
+
+	if let Some(n) = matches.value_of("Max Depth") {
+		opts.max_depth = Some(n.parse().unwrap());
+	}
+
+	if let Some(n) = matches.value_of("Max Attributes") {
+		opts.max_attributes = Some(n.parse().unwrap());
+	}
+
+	if let Some(n) = matches.value_of("Max Text Size") {
+		opts.max_text_size = Some(n.parse().unwrap());
+	}
+
+	opts.validate = matches.is_present("Validate");
+	if let Err(e) = load_schemas(&matches, &mut opts) {
+		eprintln!("{}", e);
+		return EXIT_FAILURE;
+	}
+	opts.invalid_paths = matches.is_present("Invalid Paths");
+
+	opts.keep_all_whitespace = matches.is_present("Keep All Whitespace");
+	opts.merge_cdata = matches.is_present("Merge CDATA");
+	if matches.is_present("Normalize Space") {
+		opts.text_normalization = xmlparse::TextNormalization::NormalizeSpace;
+	} else if matches.is_present("Trim") {
+		opts.text_normalization = xmlparse::TextNormalization::Trim;
+	}
+	opts.text_buffer_size = matches.value_of("Text Buffer Size").map(|n| n.parse().unwrap());
+
+	opts.output_format = match matches.value_of("Output Format") {
+		Some("xml2") => xmlparse::OutputFormat::Xml2,
+		Some("jsonl") => xmlparse::OutputFormat::Jsonl,
+		_ => xmlparse::OutputFormat::Path,
+	};
+	opts.name_format = match matches.value_of("Name Format") {
+		Some("local") => xmlparse::NameFormat::Local,
+		Some("clark") => xmlparse::NameFormat::Clark,
+		_ => xmlparse::NameFormat::AsIs,
+	};
+	opts.namespace_lines = matches.is_present("Namespace Lines");
+	opts.index_mode = match matches.value_of("Sibling Indices") {
+		Some("always") => xmlparse::IndexMode::Always,
+		Some("repeated") => xmlparse::IndexMode::Repeated,
+		_ => xmlparse::IndexMode::Never,
+	};
+
+	opts.line_numbers = matches.is_present("Line Numbers");
+	opts.byte_offsets = matches.is_present("Byte Offsets");
+
+	let files: Vec<&str> = matches.values_of("FILES").map(|f| f.collect()).unwrap_or_else(|| vec!["-"]);
+	opts.with_filename = match (matches.is_present("With Filename"), matches.is_present("No Filename")) {
+		(true, _) => true,
+		(_, true) => false,
+		_ => files.len() > 1,
+	};
+	if let Some(separator) = matches.value_of("Filename Separator") {
+		opts.filename_separator = separator.to_owned();
+	}
+	opts.file_markers = matches.is_present("File Markers");
+
+	if let Some(patterns) = list_values_of(&matches, &cli_matches, "Include") {
+		opts.include = patterns.map(xmlparse::Pattern::new).collect();
+	}
+
+	if let Some(patterns) = list_values_of(&matches, &cli_matches, "Exclude") {
+		opts.exclude = patterns.map(xmlparse::Pattern::new).collect();
+	}
+
+	let output: Box<dyn Write> = match matches.value_of("Output File") {
+		Some(path) => match File::create(path) {
+			Ok(file) => Box::new(file),
+			Err(e) => {
+				eprintln!("{}: {}", path, e);
+				return EXIT_FAILURE;
+			},
+		},
+		None => Box::new(stdout()),
+	};
+	let mut output = Output { inner: output, failed: false };
+
+	let report = Report { quiet: matches.is_present("Quiet"), strict: matches.is_present("Strict") };
+
+	let jobs = matches.value_of("Jobs").map_or(1, |n| n.parse().unwrap());
+	if jobs > 1 && files.len() > 1 {
+		let ordered = !matches.is_present("Unordered");
+		return parse_files_parallel(&files, jobs, ordered, &mut output, &opts, &report);
+	}
+
+	// Like grep, the files that can't be read don't stop the others from being parsed
+	let mut exit_code = 0;
+	for file in files {
+		let result = xmlparse::write_nodes(file.to_owned(), &mut output, &opts);
+		exit_code = exit_code.max(report.result(file, result));
+		if output.failed {
+			return EXIT_FAILURE;
+		}
+	}
+
+	exit_code
+}
+
+// Remembers whether a write failed, the output errors are told apart from the errors
+// of the input files this way
+struct Output {
+	inner: Box<dyn Write>,
+	failed: bool,
+}
+
+impl Write for Output {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let result = self.inner.write(buf);
+		self.failed |= result.is_err();
+		result
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		let result = self.inner.flush();
+		self.failed |= result.is_err();
+		result
+	}
+}
+
+// Each worker parses a file at a time into a buffer of its own. The buffers are
+// written by the main thread, in the order of the files unless `ordered` is false.
+fn parse_files_parallel(files: &[&str], jobs: usize, ordered: bool, output: &mut dyn Write, opts: &xmlparse::ProgramOpts, report: &Report) -> i32 {
+	xmlparse::init_parser();
+
+	let next_file = AtomicUsize::new(0);
+	let cancelled = AtomicBool::new(false);
+	let (sender, receiver) = mpsc::channel();
+
+	thread::scope(|scope| {
+		for _ in 0..jobs.min(files.len()) {
+			let sender = sender.clone();
+			let (next_file, cancelled) = (&next_file, &cancelled);
+			scope.spawn(move || {
+				while !cancelled.load(Ordering::Relaxed) {
+					let i = next_file.fetch_add(1, Ordering::Relaxed);
+					if i >= files.len() {
+						break;
+					}
+
+					let mut buffer = Vec::new();
+					let result = xmlparse::write_nodes(files[i].to_owned(), &mut buffer, opts);
+					if sender.send((i, buffer, result)).is_err() {
+						break;
+					}
+				}
+			});
+		}
+		drop(sender);
 
+		// Returns `None` when writing the output fails, the files that can't be read
+		// don't stop the others
+		let mut emit = |i: usize, buffer: Vec<u8>, result| {
+			if let Err(e) = output.write_all(&buffer) {
+				eprintln!("{}: {}", display_name(files[i]), e);
+				return None;
+			}
+
+			Some(report.result(files[i], result))
+		};
+
+		// Files parsed ahead of their turn wait here to be written
+		let mut pending = BTreeMap::new();
+		let mut next_output = 0;
+		let mut exit_code = 0;
+		for (i, buffer, result) in receiver.iter() {
+			let code = if ordered {
+				pending.insert(i, (buffer, result));
+
+				let mut code = Some(0);
+				while let Some((buffer, result)) = pending.remove(&next_output) {
+					next_output += 1;
+					code = code.zip(emit(next_output - 1, buffer, result)).map(|(a, b)| a.max(b));
+					if code.is_none() {
+						break;
+					}
+				}
+				code
+			} else {
+				emit(i, buffer, result)
+			};
+
+			match code {
+				Some(code) => exit_code = exit_code.max(code),
+				None => {
+					// The workers stop after the file they are parsing
+					cancelled.store(true, Ordering::Relaxed);
+					return EXIT_FAILURE;
+				},
+			}
+		}
+
+		exit_code
+	})
+}
+
+struct Report {
+	quiet: bool,
+	strict: bool,
+}
+
+impl Report {
+	// Prints the diagnostics of a file and returns the exit status they result in
+	fn result(&self, file: &str, result: xmlparse::ParseResult) -> i32 {
+		let name = display_name(file);
+		let (exit_code, diagnostics) = match result {
+			Ok(diagnostics) if self.strict && !diagnostics.is_empty() => (EXIT_PARSE_ERROR, diagnostics),
+			Ok(diagnostics) => (0, diagnostics),
+			Err(xmlparse::Error::Parse(diagnostics)) => (EXIT_PARSE_ERROR, diagnostics),
+			Err(xmlparse::Error::Io(e)) => {
+				eprintln!("{}: {}", name, e);
+				return EXIT_FAILURE;
+			},
+		};
+
+		if !self.quiet {
+			print_diagnostics(name, &diagnostics);
+		}
+
+		exit_code
+	}
+}
+
+fn display_name(file: &str) -> &str {
+	if file == "-" { "<stdin>" } else { file }
+}
+
+fn print_diagnostics(file: &str, diagnostics: &[Diagnostic]) {
+	for diagnostic in diagnostics {
+		match (diagnostic.line, diagnostic.column) {
+			(Some(line), Some(column)) => eprintln!("{}:{}:{}: {}", file, line, column, diagnostic),
+			(Some(line), None) => eprintln!("{}:{}: {}", file, line, diagnostic),
+			_ => eprintln!("{}: {}", file, diagnostic),
+		}
+	}
+}
+
+fn get_matches() -> Result<(ArgMatches<'static>, ArgMatches<'static>), i32> {
+	let mut args = std::env::args_os();
+	let bin = args.next().unwrap_or_else(|| OsString::from("xmlparse"));
+	let load_config = || defaults::config_path().map(defaults::load_config).transpose().map(Option::flatten);
+	matches_with_defaults(bin, args.collect(), defaults::env_args, load_config)
+}
+
+// Parses the command line after the defaults read from the configuration file and
+// `XMLPARSE_OPTS`. The defaults are checked on their own first so that their errors
+// can be told apart. Also returns the matches of the command line alone.
+fn matches_with_defaults<E, C>(bin: OsString, cli_args: Vec<OsString>, env_args: E, load_config: C) -> Result<(ArgMatches<'static>, ArgMatches<'static>), i32>
+	where E: FnOnce() -> Result<Vec<String>, String>, C: FnOnce() -> Result<Option<defaults::Config>, String>
+{
+	let with_bin = |args: &[OsString]| -> Vec<OsString> { std::iter::once(bin.clone()).chain(args.iter().cloned()).collect() };
+
+	let cli_matches = parse_args(with_bin(&cli_args), None)?;
+	if cli_matches.is_present("No Config") {
+		return Ok((cli_matches.clone(), cli_matches));
+	}
+
+	let env_args: Vec<OsString> = match env_args() {
+		Ok(args) => args.into_iter().map(OsString::from).collect(),
+		Err(e) => {
+			eprintln!("{}: {}", defaults::ENV_VAR, e);
+			return Err(EXIT_FAILURE);
+		},
+	};
+	let env_matches = parse_args(with_bin(&env_args), Some(defaults::ENV_VAR))?;
+
+	let profile = cli_matches.value_of("Profile").or_else(|| env_matches.value_of("Profile"));
+	let config = match load_config() {
+		Ok(config) => config,
+		Err(e) => {
+			eprintln!("{}", e);
+			return Err(EXIT_FAILURE);
+		},
+	};
+
+	let config_args: Vec<OsString> = match (&config, profile) {
+		(Some(config), _) => match config.args(profile) {
+			Ok(args) => args.into_iter().map(OsString::from).collect(),
+			Err(e) => {
+				eprintln!("{}", e);
+				return Err(EXIT_FAILURE);
+			},
+		},
+		(None, Some(profile)) => {
+			eprintln!("Profile `{}` not found, there is no configuration file", profile);
+			return Err(EXIT_FAILURE);
+		},
+		(None, None) => Vec::new(),
+	};
+
+	if let Some(config) = &config {
+		parse_args(with_bin(&config_args), Some(&config.path().to_string_lossy()))?;
+	}
+
+	let args: Vec<OsString> = config_args.into_iter().chain(env_args).chain(cli_args).collect();
+	Ok((parse_args(with_bin(&args), None)?, cli_matches))
+}
+
+// The values of an option given more than once. Those given on the command line replace
+// the ones of the defaults.
+fn list_values_of<'a>(matches: &'a ArgMatches, cli_matches: &'a ArgMatches, name: &str) -> Option<clap::Values<'a>> {
+	if cli_matches.is_present(name) {
+		cli_matches.values_of(name)
+	} else {
+		matches.values_of(name)
+	}
+}
+
+// Returns the exit status when the arguments are invalid or only help was requested.
+// `source` is where the arguments came from when not from the command line.
+fn parse_args(args: Vec<OsString>, source: Option<&str>) -> Result<ArgMatches<'static>, i32> {
+	match cli::build_cli().get_matches_from_safe(args) {
+		Ok(matches) => Ok(matches),
+		Err(e) if e.kind == ErrorKind::HelpDisplayed || e.kind == ErrorKind::VersionDisplayed => {
+			println!("{}", e.message);
+			Err(0)
+		},
+		Err(e) => {
+			match source {
+				Some(source) => eprintln!("{}: {}", source, e.message),
+				None => eprintln!("{}", e.message),
+			}
+			Err(EXIT_FAILURE)
+		},
+	}
+}
+
+// The DTD and the schemas are loaded once and shared by every file
+fn load_schemas(matches: &ArgMatches, opts: &mut xmlparse::ProgramOpts) -> Result<(), std::io::Error> {
+	opts.dtd = matches.value_of("DTD").map(xmlparse::Dtd::load).transpose()?;
+	opts.xsd = matches.value_of("XSD").map(xmlparse::XmlSchema::load).transpose()?;
+	opts.rng = matches.value_of("RNG").map(xmlparse::RelaxNgSchema::load).transpose()?;
+	Ok(())
+}
+
+// Options shared with the subcommands that control how values are printed. Only the
+// options that are present are set, so that the options given after the subcommand
+// override the ones before it.
+fn parse_value_opts(matches: &ArgMatches, opts: &mut xmlparse::ProgramOpts) {
 	if let Some(string) = matches.value_of("Space Character") {
 		opts.space_map = string.chars().nth(0).unwrap();
 	}
@@ -47,23 +443,121 @@ fn real_main() -> i32 {
 		opts.newline_map = string.chars().nth(2).unwrap();
 	}
 
-	if matches.is_present("Print Mappings") {
-		println!("{}{}{}", opts.space_map, opts.tab_map, opts.newline_map);
-		return 0;
+	if matches.is_present("Escape Non ASCII") {
+		opts.escape_non_ascii = true;
 	}
 
-	opts.map_whitespace = matches.is_present("Map Whitespace");
-	opts.compress_whitespace = matches.is_present("Compress Whitespace");
-	opts.keep_all_whitespace = matches.is_present("Keep All Whitespace");
+	match matches.value_of("Escape Mode") {
+		Some("c") => opts.escape_mode = xmlparse::EscapeMode::C,
+		Some("xml") => opts.escape_mode = xmlparse::EscapeMode::Xml,
+		_ => {},
+	}
+
+	if opts.escape_non_ascii && opts.escape_mode == xmlparse::EscapeMode::None {
+		opts.escape_mode = xmlparse::EscapeMode::C;
+	}
+
+	if matches.is_present("Map Whitespace") {
+		opts.map_whitespace = true;
+	}
+
+	if matches.is_present("Compress Whitespace") {
+		opts.compress_whitespace = true;
+	}
 
 	if let Some(level) = matches.value_of("Compression Level") {
 		let level: usize = level.parse().unwrap();
 		opts.compress_level = level;
 	}
+}
+
+// Runs a subcommand that converts the output of xmlparse
+fn convert_files<F>(matches: &ArgMatches, opts: &xmlparse::ProgramOpts, convert: F) -> i32
+	where F: Fn(&mut dyn BufRead, &mut BufWriter<StdoutLock>, &xmlparse::ProgramOpts) -> Result<(), std::io::Error>
+{
+	let stdout = stdout();
+	let mut stdout = BufWriter::new(stdout.lock());
 
-	for file in matches.values_of("FILES").unwrap().collect::<Vec<_>>() {
-		xmlparse::print_nodes(file.to_owned(), &opts);
+	let files = matches.values_of("FILES").map(|f| f.collect()).unwrap_or_else(|| vec!["-"]);
+	for file in files {
+		let result = if file == "-" {
+			convert(&mut stdin().lock(), &mut stdout, opts)
+		} else {
+			File::open(file).and_then(|f| convert(&mut BufReader::new(f), &mut stdout, opts))
+		};
+
+		if let Err(e) = result {
+			eprintln!("{}: {}", file, e);
+			return match e.kind() {
+				std::io::ErrorKind::InvalidData => EXIT_PARSE_ERROR,
+				_ => EXIT_FAILURE,
+			};
+		}
 	}
 
 	0
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{ defaults, list_values_of, matches_with_defaults };
+
+	use clap::ArgMatches;
+
+	use std::ffi::OsString;
+	use std::path::PathBuf;
+
+	// The matches of all the arguments and of the command line alone
+	fn matches(cli: &str, env: &str, config: &str) -> (ArgMatches<'static>, ArgMatches<'static>) {
+		let cli_args = cli.split_whitespace().map(OsString::from).collect();
+		let env_args = || Ok(env.split_whitespace().map(String::from).collect());
+		let load_config = || defaults::Config::parse(PathBuf::from("config.toml"), config).map(Some);
+		matches_with_defaults(OsString::from("xmlparse"), cli_args, env_args, load_config).unwrap()
+	}
+
+	fn format(cli: &str, env: &str, config: &str) -> Option<String> {
+		matches(cli, env, config).0.value_of("Output Format").map(String::from)
+	}
+
+	fn includes(cli: &str, env: &str, config: &str) -> Vec<String> {
+		let (matches, cli_matches) = matches(cli, env, config);
+		let values = list_values_of(&matches, &cli_matches, "Include");
+		values.map_or_else(Vec::new, |values| values.map(String::from).collect())
+	}
+
+	#[test]
+	fn values_precedence() {
+		let config = "format = \"xml2\"";
+		assert_eq!(format("", "", config).as_deref(), Some("xml2"));
+		assert_eq!(format("", "--format=jsonl", config).as_deref(), Some("jsonl"));
+		assert_eq!(format("--format path", "--format=jsonl", config).as_deref(), Some("path"));
+		assert_eq!(format("--no-config", "--format=jsonl", config), None);
+	}
+
+	#[test]
+	fn flags_precedence() {
+		let mapped = |cli, env, config| matches(cli, env, config).0.is_present("Map Whitespace");
+		assert!(mapped("", "", "map-whitespace = true"));
+		assert!(!mapped("--no-map-whitespace", "", "map-whitespace = true"));
+		assert!(!mapped("", "--no-map-whitespace", "map-whitespace = true"));
+		assert!(mapped("-m", "--no-map-whitespace", ""));
+		assert!(mapped("", "-m", "map-whitespace = false"));
+		assert!(!mapped("--no-config", "-m", "map-whitespace = true"));
+	}
+
+	#[test]
+	fn lists_replaced() {
+		let config = "include = [\"a\"]";
+		assert_eq!(includes("", "", config), ["a"]);
+		assert_eq!(includes("", "--include=b", config), ["a", "b"]);
+		assert_eq!(includes("--include c --include d", "--include=b", config), ["c", "d"]);
+	}
+
+	#[test]
+	fn profile_options() {
+		let config = "format = \"xml2\"\n\n[profiles.json]\nformat = \"jsonl\"";
+		assert_eq!(format("--profile json", "", config).as_deref(), Some("jsonl"));
+		assert_eq!(format("", "--profile=json", config).as_deref(), Some("jsonl"));
+		assert_eq!(format("--profile json --format path", "", config).as_deref(), Some("path"));
+	}
+}
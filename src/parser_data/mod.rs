@@ -20,73 +20,275 @@ mod xml_tag;
 
 pub use xml_tag::XmlTag;
 
-use crate::config::ProgramOpts;
+use crate::config::{ IndexMode, ProgramOpts };
+use crate::filter::Visibility;
 
 use std::io::Write;
-use std::io::{ stdout, Stdout, BufWriter };
 
-pub struct XmlTags<'a>(Vec<XmlTag<'a>>);
+pub struct XmlTags(Vec<XmlTag>);
 
-pub struct ParserData<'a> {
-	result: u32,
+// Where an event is located in the document
+#[derive(Clone, Copy, Default)]
+pub struct Location {
+	pub line: u32,
+	pub column: u32,
+	pub byte_offset: u64,
+}
+
+// The file name, location annotations and path printed at the start of every line
+pub struct LinePrefix<'a> {
+	opts: &'a ProgramOpts,
+	filename: Option<&'a str>,
+	location: Option<Location>,
+	tags: &'a XmlTags,
+}
+
+pub struct ParserData<'a, W: Write> {
+	result: Result<(), std::io::Error>,
 	opts: &'a ProgramOpts,
-	tags: XmlTags<'a>,
-	stdout: BufWriter<Stdout>,
+	tags: XmlTags,
+	out: W,
+	// The name of the file printed at the start of every line
+	filename: Option<String>,
+	// The name of the element that was just closed, if nothing was printed since
+	closed_sibling: Option<String>,
+	// The location of the current event, only tracked when it is printed
+	location: Option<Location>,
+	// Text that is printed once all of it is known, and where it starts
+	text: String,
+	text_location: Option<Location>,
+	// The depth inside an excluded subtree, its elements are not pushed
+	skip_depth: usize,
 }
 
-impl<'a> ParserData<'a> {
-	pub fn with_capacity(cap: usize, opts: &'a ProgramOpts) -> Self {
+impl<'a, W: Write> ParserData<'a, W> {
+	pub fn with_capacity(cap: usize, opts: &'a ProgramOpts, out: W) -> Self {
 		ParserData {
-			result: 0,
+			result: Ok(()),
 			opts,
 			tags: XmlTags(Vec::with_capacity(cap)),
-			stdout: BufWriter::new(stdout()),
+			out,
+			filename: None,
+			closed_sibling: None,
+			location: None,
+			text: String::new(),
+			text_location: None,
+			skip_depth: 0,
+		}
+	}
+
+	pub fn opts_tags_and_buf_mut(&mut self) -> (&ProgramOpts, LinePrefix<'_>, &mut W) {
+		let prefix = LinePrefix { opts: self.opts, filename: self.filename.as_deref(), location: self.location, tags: &self.tags };
+		(self.opts, prefix, &mut self.out)
+	}
+
+	pub fn set_filename(&mut self, filename: Option<String>) {
+		self.filename = filename;
+	}
+
+	pub fn current_location(&self) -> Option<Location> {
+		self.location
+	}
+
+	pub fn set_location(&mut self, location: Option<Location>) {
+		self.location = location;
+	}
+
+	// Returns the writer, or the first error that occurred while writing to it
+	pub fn finish(self) -> Result<W, std::io::Error> {
+		let out = self.out;
+		self.result.map(|_| out)
+	}
+
+	// Runs `print` unless a previous write failed. Stores the error if it fails.
+	pub fn try_print<F>(&mut self, print: F)
+		where F: FnOnce(&mut Self) -> Result<(), std::io::Error>
+	{
+		if self.result.is_err() {
+			return;
 		}
+
+		if let Err(e) = print(self) {
+			self.result = Err(e);
+		}
+	}
+
+	pub fn push_text(&mut self, text: &str) {
+		if self.text.is_empty() {
+			self.text_location = self.location;
+		}
+
+		self.text.push_str(text);
+	}
+
+	pub fn text_len(&self) -> usize {
+		self.text.len()
 	}
 
-	pub fn opts_tags_and_buf_mut(&mut self) -> (&ProgramOpts, &mut XmlTags<'a>, &mut BufWriter<Stdout>) {
-		(self.opts, &mut self.tags, &mut self.stdout)
+	pub fn take_text(&mut self) -> Option<(String, Option<Location>)> {
+		if self.text.is_empty() {
+			return None;
+		}
+
+		Some((std::mem::take(&mut self.text), self.text_location))
 	}
 
-	pub fn opts(&mut self) -> &ProgramOpts {
+	pub fn opts(&self) -> &'a ProgramOpts {
 		self.opts
 	}
 
-	pub fn last_tag(&self) -> Option<&XmlTag<'a>> {
+	pub fn last_tag(&self) -> Option<&XmlTag> {
 		self.tags.0.last()
 	}
 
-	pub fn last_tag_mut(&mut self) -> Option<&mut XmlTag<'a>> {
+	pub fn last_tag_mut(&mut self) -> Option<&mut XmlTag> {
 		self.tags.0.last_mut()
 	}
 
-	pub fn push_tag(&mut self, node: XmlTag<'a>) {
+	pub fn push_tag(&mut self, mut node: XmlTag) {
+		// Element lines are printed lazily, so remember where the element starts
+		node.set_location(self.location);
+
+		if self.opts.index_mode != IndexMode::Never {
+			let index = match self.last_tag_mut() {
+				Some(parent) => parent.count_child(node.name()),
+				None => 1,
+			};
+
+			if self.opts.index_mode == IndexMode::Always || index > 1 {
+				node.set_index(index);
+			}
+		}
+
 		self.tags.0.push(node)
 	}
 
-	pub fn pop_tag(&mut self) -> Option<XmlTag<'a>> {
+	pub fn pop_tag(&mut self) -> Option<XmlTag> {
 		self.tags.0.pop()
 	}
 
+	pub fn set_closed_sibling(&mut self, name: Option<String>) {
+		self.closed_sibling = name;
+	}
+
+	pub fn take_closed_sibling(&mut self) -> Option<String> {
+		self.closed_sibling.take()
+	}
+
+	// Matches the path of a new element against the include and exclude patterns
+	pub fn element_visibility(&self, name: &str) -> Visibility {
+		if self.skip_depth > 0 {
+			return Visibility::Excluded;
+		}
+
+		let (include, exclude) = (&self.opts.include, &self.opts.exclude);
+		if include.is_empty() && exclude.is_empty() && !self.opts.invalid_paths {
+			return Visibility::Visible;
+		}
+
+		// Only the paths of the invalid elements are printed, the exclude patterns
+		// still apply
+		let path: Vec<&str> = self.tags.0.iter().map(|t| t.name()).chain(std::iter::once(name)).collect();
+		if exclude.iter().any(|p| p.matches(&path)) {
+			Visibility::Excluded
+		} else if self.opts.invalid_paths {
+			Visibility::Hidden
+		} else if self.content_visible() || include.iter().any(|p| p.matches(&path)) {
+			Visibility::Visible
+		} else {
+			Visibility::Hidden
+		}
+	}
+
+	// Whether the events inside the current element are printed
+	pub fn content_visible(&self) -> bool {
+		match self.last_tag() {
+			_ if self.skip_depth > 0 => false,
+			Some(tag) => tag.visible(),
+			None => self.opts.include.is_empty() && !self.opts.invalid_paths,
+		}
+	}
+
+	pub fn skip_element(&mut self) {
+		self.skip_depth += 1;
+	}
+
+	// Returns false when not inside an excluded subtree
+	pub fn end_skipped_element(&mut self) -> bool {
+		if self.skip_depth == 0 {
+			return false;
+		}
+
+		self.skip_depth -= 1;
+		true
+	}
+
+	pub fn in_skipped_element(&self) -> bool {
+		self.skip_depth > 0
+	}
+
 	pub fn tags_is_empty(&self) -> bool {
 		self.tags.0.is_empty()
 	}
 
-	pub fn print_last_tag(&mut self) {
+	pub fn print_last_tag(&mut self) -> Result<(), std::io::Error> {
 		if self.tags_is_empty() {
-			return;
+			return Ok(());
 		}
 
 		let tag = self.last_tag().unwrap();
 		if !tag.printed() {
-			writeln!(self.stdout, "{}", self.tags).unwrap();
+			let prefix = LinePrefix { opts: self.opts, filename: self.filename.as_deref(), location: tag.location(), tags: &self.tags };
+			writeln!(self.out, "{}", prefix)?;
 			self.last_tag_mut().unwrap().set_printed(true);
 		}
+
+		Ok(())
 	}
 }
 
-impl<'a> std::fmt::Display for XmlTags<'a> {
+impl std::fmt::Display for XmlTags {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
 		self.0.iter().try_for_each(|t| write!(f, "/{}", t))
 	}
 }
+
+impl XmlTags {
+	pub fn iter(&self) -> std::slice::Iter<'_, XmlTag> {
+		self.0.iter()
+	}
+}
+
+impl<'a> LinePrefix<'a> {
+	pub fn filename(&self) -> Option<&'a str> {
+		self.filename
+	}
+
+	pub fn location(&self) -> Option<Location> {
+		self.location
+	}
+
+	pub fn tags(&self) -> &'a XmlTags {
+		self.tags
+	}
+}
+
+impl<'a> std::fmt::Display for LinePrefix<'a> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		if let Some(filename) = self.filename {
+			write!(f, "{}{}", filename, self.opts.filename_separator)?;
+		}
+
+		if let Some(location) = self.location {
+			if self.opts.line_numbers {
+				write!(f, "{}:{}:", location.line, location.column)?;
+			}
+
+			if self.opts.byte_offsets {
+				write!(f, "{}:", location.byte_offset)?;
+			}
+		}
+
+		write!(f, "{}", self.tags)
+	}
+}
@@ -16,15 +16,58 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use super::Location;
+
+use std::collections::HashMap;
+
 #[derive(Default)]
-pub struct XmlTag<'a> {
-	name: &'a str,
+pub struct XmlTag {
+	name: String,
 	printed: bool,
+	// The position among the siblings with the same name, printed as `name[index]`
+	index: Option<usize>,
+	// The number of children seen so far for each name
+	children: HashMap<String, usize>,
+	location: Option<Location>,
+	// Whether the events inside the element are printed
+	visible: bool,
 }
 
-impl<'a> XmlTag<'a> {
-	pub fn from(name: &'a str, printed: bool) -> Self {
-		XmlTag { name, printed }
+impl XmlTag {
+	pub fn from(name: &str, printed: bool) -> Self {
+		XmlTag { name: name.to_owned(), printed, index: None, children: HashMap::new(), location: None, visible: true }
+	}
+
+	pub fn visible(&self) -> bool {
+		self.visible
+	}
+
+	pub fn set_visible(&mut self, val: bool) {
+		self.visible = val;
+	}
+
+	pub fn location(&self) -> Option<Location> {
+		self.location
+	}
+
+	pub fn set_location(&mut self, location: Option<Location>) {
+		self.location = location;
+	}
+
+	pub fn index(&self) -> Option<usize> {
+		self.index
+	}
+
+	pub fn set_index(&mut self, index: usize) {
+		self.index = Some(index);
+	}
+
+	// Counts a new child with the given name and returns its position among its
+	// siblings with the same name, starting from 1
+	pub fn count_child(&mut self, name: &str) -> usize {
+		let count = self.children.entry(name.to_owned()).or_insert(0);
+		*count += 1;
+		*count
 	}
 
 	pub fn printed(&self) -> bool {
@@ -35,13 +78,16 @@ impl<'a> XmlTag<'a> {
 		self.printed = val;
 	}
 
-	pub fn name(&self) -> &'a str {
+	pub fn name(&self) -> &str {
 		&self.name
 	}
 }
 
-impl<'a> std::fmt::Display for XmlTag<'a> {
+impl std::fmt::Display for XmlTag {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		write!(f, "{}", self.name)
+		match self.index {
+			Some(index) => write!(f, "{}[{}]", self.name, index),
+			None => write!(f, "{}", self.name),
+		}
 	}
 }
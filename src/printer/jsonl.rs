@@ -0,0 +1,269 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::handler::{ Attribute, Namespace, QName };
+
+use crate::parser_data::{ ParserData, XmlTag };
+
+use crate::config::IndexMode;
+
+use super::{ format_name, is_only_whitespace };
+
+use std::io::Write;
+
+// Every event is a single JSON object of the form
+// {"file":"...","path":[...],"type":"...",<fields>,<metadata>}, where the path holds
+// the names of the open elements. Values are printed as JSON strings, so the whitespace and
+// escaping options of the line formats don't apply.
+impl<'a, W: Write> ParserData<'a, W> {
+	pub(super) fn print_jsonl_start_element(&mut self, name: &QName, attrs: &[Attribute], namespaces: &[Namespace]) -> Result<(), std::io::Error> {
+		let name_format = self.opts().name_format;
+		self.push_tag(XmlTag::from(&format_name(name, name_format), true));
+		self.print_record("element", &[])?;
+
+		for ns in namespaces {
+			self.print_record("namespace", &[("prefix", ns.prefix), ("uri", Some(ns.uri))])?;
+		}
+
+		for attr in attrs {
+			let name = format_name(&attr.name, name_format);
+			self.print_record("attr", &[("name", Some(&name)), ("value", Some(attr.value))])?;
+		}
+
+		Ok(())
+	}
+
+	pub(super) fn print_jsonl_end_element(&mut self, name: &QName) -> Result<(), std::io::Error> {
+		let name = format_name(name, self.opts().name_format);
+		if self.last_tag().map(|tag| tag.name()) == Some(&*name) {
+			self.pop_tag();
+		}
+
+		Ok(())
+	}
+
+	pub(super) fn print_jsonl_characters(&mut self, chars: &str) -> Result<(), std::io::Error> {
+		if !self.opts().keep_all_whitespace && is_only_whitespace(chars) {
+			return Ok(());
+		}
+
+		self.print_record("text", &[("value", Some(chars))])
+	}
+
+	pub(super) fn print_jsonl_cdata(&mut self, data: &str) -> Result<(), std::io::Error> {
+		self.print_record("cdata", &[("value", Some(data))])
+	}
+
+	pub(super) fn print_jsonl_comment(&mut self, comment: &str) -> Result<(), std::io::Error> {
+		self.print_record("comment", &[("value", Some(comment))])
+	}
+
+	pub(super) fn print_jsonl_processing_instruction(&mut self, target: &str, data: &str) -> Result<(), std::io::Error> {
+		self.print_record("pi", &[("target", Some(target)), ("data", Some(data))])
+	}
+
+	pub(super) fn print_jsonl_invalid(&mut self, message: &str) -> Result<(), std::io::Error> {
+		self.print_record("invalid", &[("message", Some(message))])
+	}
+
+	pub(super) fn print_jsonl_file_marker(&mut self, record_type: &str, filename: &str) -> Result<(), std::io::Error> {
+		let write_buf = self.opts_tags_and_buf_mut().2;
+		write!(write_buf, "{{\"type\":\"{}\",\"file\":", record_type)?;
+		write_json_string(write_buf, filename)?;
+		write_buf.write_all(b"}\n")
+	}
+
+	// Fields without a value are printed as null
+	fn print_record(&mut self, record_type: &str, fields: &[(&str, Option<&str>)]) -> Result<(), std::io::Error> {
+		let (opts, prefix, write_buf) = self.opts_tags_and_buf_mut();
+		let tags = prefix.tags();
+
+		write_buf.write_all(b"{")?;
+		if let Some(filename) = prefix.filename() {
+			write_buf.write_all(b"\"file\":")?;
+			write_json_string(write_buf, filename)?;
+			write_buf.write_all(b",")?;
+		}
+
+		write_buf.write_all(b"\"path\":[")?;
+		for (i, tag) in tags.iter().enumerate() {
+			if i != 0 {
+				write_buf.write_all(b",")?;
+			}
+			write_json_string(write_buf, tag.name())?;
+		}
+
+		write!(write_buf, "],\"type\":\"{}\"", record_type)?;
+		for (name, value) in fields {
+			write!(write_buf, ",\"{}\":", name)?;
+			match value {
+				Some(value) => write_json_string(write_buf, value)?,
+				None => write_buf.write_all(b"null")?,
+			}
+		}
+
+		// Unlike the path formats every index is printed, the first of the repeated
+		// siblings has none set
+		if opts.index_mode != IndexMode::Never {
+			write_buf.write_all(b",\"indices\":[")?;
+			for (i, tag) in tags.iter().enumerate() {
+				if i != 0 {
+					write_buf.write_all(b",")?;
+				}
+				write!(write_buf, "{}", tag.index().unwrap_or(1))?;
+			}
+			write_buf.write_all(b"]")?;
+		}
+
+		if let Some(location) = prefix.location() {
+			if opts.line_numbers {
+				write!(write_buf, ",\"line\":{},\"column\":{}", location.line, location.column)?;
+			}
+			if opts.byte_offsets {
+				write!(write_buf, ",\"offset\":{}", location.byte_offset)?;
+			}
+		}
+
+		write_buf.write_all(b"}\n")
+	}
+}
+
+fn write_json_string<W: Write>(write_buf: &mut W, string: &str) -> Result<(), std::io::Error> {
+	write_buf.write_all(b"\"")?;
+
+	let bytes = string.as_bytes();
+	let mut start = 0;
+	for (i, &byte) in bytes.iter().enumerate() {
+		let escaped = match byte {
+			b'"' => "\\\"",
+			b'\\' => "\\\\",
+			b'\n' => "\\n",
+			b'\r' => "\\r",
+			b'\t' => "\\t",
+			0x08 => "\\b",
+			0x0c => "\\f",
+			0x00..=0x1f => "",
+			_ => continue,
+		};
+
+		write_buf.write_all(&bytes[start..i])?;
+		if escaped.is_empty() {
+			write!(write_buf, "\\u{:04x}", byte)?;
+		} else {
+			write_buf.write_all(escaped.as_bytes())?;
+		}
+		start = i + 1;
+	}
+
+	write_buf.write_all(&bytes[start..])?;
+	write_buf.write_all(b"\"")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::write_json_string;
+
+	use crate::config::{ IndexMode, OutputFormat, ProgramOpts };
+	use crate::handler::{ Namespace, QName };
+	use crate::parser_data::{ Location, ParserData, XmlTag };
+
+	fn json_string(string: &str) -> String {
+		let mut out = Vec::new();
+		write_json_string(&mut out, string).unwrap();
+		String::from_utf8(out).unwrap()
+	}
+
+	fn records<F>(opts: &ProgramOpts, print: F) -> String
+		where F: FnOnce(&mut ParserData<Vec<u8>>) -> Result<(), std::io::Error>
+	{
+		let mut data = ParserData::with_capacity(4, opts, Vec::new());
+		print(&mut data).unwrap();
+		String::from_utf8(data.finish().unwrap()).unwrap()
+	}
+
+	#[test]
+	fn escaped_strings() {
+		assert_eq!(json_string(""), r#""""#);
+		assert_eq!(json_string(r#"a"b\c"#), r#""a\"b\\c""#);
+		assert_eq!(json_string("\n\r\t\u{8}\u{c}"), r#""\n\r\t\b\f""#);
+		assert_eq!(json_string("\u{0}\u{1}x\u{1f}"), r#""\u0000\u0001x\u001f""#);
+		assert_eq!(json_string("\u{7f}é€😀"), "\"\u{7f}é€😀\"");
+	}
+
+	#[test]
+	fn required_fields() {
+		let opts = ProgramOpts { output_format: OutputFormat::Jsonl, ..ProgramOpts::default() };
+		let out = records(&opts, |data| {
+			data.print_jsonl_characters("x")?;
+			data.push_tag(XmlTag::from("a", true));
+			data.print_jsonl_comment("c\"")
+		});
+
+		assert_eq!(out, "{\"path\":[],\"type\":\"text\",\"value\":\"x\"}\n\
+			{\"path\":[\"a\"],\"type\":\"comment\",\"value\":\"c\\\"\"}\n");
+	}
+
+	#[test]
+	fn null_fields() {
+		let opts = ProgramOpts { output_format: OutputFormat::Jsonl, ..ProgramOpts::default() };
+		let name = QName { local_name: "a", prefix: None, uri: Some("u") };
+		let out = records(&opts, |data| data.print_jsonl_start_element(&name, &[], &[Namespace { prefix: None, uri: "u" }]));
+
+		assert_eq!(out, "{\"path\":[\"a\"],\"type\":\"element\"}\n\
+			{\"path\":[\"a\"],\"type\":\"namespace\",\"prefix\":null,\"uri\":\"u\"}\n");
+	}
+
+	#[test]
+	fn optional_fields() {
+		let opts = ProgramOpts {
+			output_format: OutputFormat::Jsonl,
+			index_mode: IndexMode::Repeated,
+			line_numbers: true,
+			byte_offsets: true,
+			..ProgramOpts::default()
+		};
+
+		let out = records(&opts, |data| {
+			data.set_filename(Some(String::from("a \"b\".xml")));
+			data.push_tag(XmlTag::from("a", true));
+			data.push_tag(XmlTag::from("b", true));
+			data.pop_tag();
+			data.push_tag(XmlTag::from("b", true));
+			data.set_location(Some(Location { line: 3, column: 4, byte_offset: 5 }));
+			data.print_jsonl_characters("x")
+		});
+
+		assert_eq!(out, "{\"file\":\"a \\\"b\\\".xml\",\"path\":[\"a\",\"b\"],\"type\":\"text\",\"value\":\"x\",\
+			\"indices\":[1,2],\"line\":3,\"column\":4,\"offset\":5}\n");
+	}
+
+	#[test]
+	fn location_fields_apart() {
+		let location = Some(Location { line: 3, column: 4, byte_offset: 5 });
+		let print = |data: &mut ParserData<Vec<u8>>| {
+			data.set_location(location);
+			data.print_jsonl_cdata("x")
+		};
+
+		let opts = ProgramOpts { output_format: OutputFormat::Jsonl, line_numbers: true, ..ProgramOpts::default() };
+		assert_eq!(records(&opts, print), "{\"path\":[],\"type\":\"cdata\",\"value\":\"x\",\"line\":3,\"column\":4}\n");
+
+		let opts = ProgramOpts { output_format: OutputFormat::Jsonl, byte_offsets: true, ..ProgramOpts::default() };
+		assert_eq!(records(&opts, print), "{\"path\":[],\"type\":\"cdata\",\"value\":\"x\",\"offset\":5}\n");
+	}
+}
@@ -0,0 +1,635 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod xml2;
+
+mod jsonl;
+
+use crate::handler::{ Attribute, Namespace, QName, XmlEventHandler };
+
+use crate::locator::Locator;
+
+use crate::parser_data::{ Location, ParserData };
+use crate::filter::Visibility;
+use crate::parser_data::XmlTag;
+
+use crate::config::{ EscapeMode, NameFormat, OutputFormat, ProgramOpts, TextNormalization };
+
+use std::borrow::Cow;
+use std::io::Write;
+
+impl<'a, W: Write> XmlEventHandler for ParserData<'a, W> {
+	fn location(&mut self, locator: &Locator) {
+		let opts = self.opts();
+		if !opts.line_numbers && !opts.byte_offsets {
+			return;
+		}
+
+		self.set_location(Some(Location {
+			line: locator.line(),
+			column: locator.column(),
+			byte_offset: if opts.byte_offsets { locator.byte_offset() } else { 0 },
+		}));
+	}
+
+	fn start_element(&mut self, name: &QName, attrs: &[Attribute], namespaces: &[Namespace]) {
+		self.flush_text();
+		if !self.filter_start_element(name) {
+			return;
+		}
+
+		let normalization = self.opts().text_normalization;
+		let values: Vec<Cow<str>>;
+		let normalized: Vec<Attribute>;
+		let attrs = if normalization == TextNormalization::None {
+			attrs
+		} else {
+			values = attrs.iter().map(|attr| normalize_text(attr.value, normalization)).collect();
+			normalized = attrs.iter().zip(&values).map(|(attr, value)| Attribute { name: attr.name, value }).collect();
+			&normalized
+		};
+
+		match self.opts().output_format {
+			OutputFormat::Path => self.try_print(|data| data.print_start_element(name, attrs, namespaces)),
+			OutputFormat::Xml2 => self.try_print(|data| data.print_xml2_start_element(name, attrs, namespaces)),
+			OutputFormat::Jsonl => self.try_print(|data| data.print_jsonl_start_element(name, attrs, namespaces)),
+		}
+	}
+
+	fn end_element(&mut self, name: &QName) {
+		self.flush_text();
+		if self.end_skipped_element() {
+			return;
+		}
+
+		if !self.content_visible() {
+			self.pop_tag();
+			return;
+		}
+
+		match self.opts().output_format {
+			OutputFormat::Path => self.try_print(|data| data.print_end_element(name)),
+			OutputFormat::Xml2 => self.try_print(|data| data.print_xml2_end_element(name)),
+			OutputFormat::Jsonl => self.try_print(|data| data.print_jsonl_end_element(name)),
+		}
+	}
+
+	fn characters(&mut self, chars: &str) {
+		if !self.content_visible() {
+			return;
+		}
+
+		// libxml2 splits text around entity references and at the end of its buffers,
+		// the parts are printed together once the next event is received
+		self.push_text(chars);
+		if matches!(self.opts().text_buffer_size, Some(size) if self.text_len() >= size) {
+			self.flush_text();
+		}
+	}
+
+	// CDATA sections are kept as they are unless merged with the text around them.
+	// xml2 has no syntax for CDATA sections.
+	fn cdata(&mut self, data: &str) {
+		let opts = self.opts();
+		if opts.merge_cdata || opts.output_format == OutputFormat::Xml2 {
+			self.characters(data);
+			return;
+		}
+
+		self.flush_text();
+		if !self.content_visible() {
+			return;
+		}
+
+		match opts.output_format {
+			OutputFormat::Jsonl => self.try_print(|parser_data| parser_data.print_jsonl_cdata(data)),
+			_ => self.try_print(|parser_data| parser_data.print_cdata(data)),
+		}
+	}
+
+	fn comment(&mut self, comment: &str) {
+		self.flush_text();
+		if !self.content_visible() {
+			return;
+		}
+
+		match self.opts().output_format {
+			OutputFormat::Path => self.try_print(|data| data.print_comment(comment)),
+			OutputFormat::Xml2 => self.try_print(|data| data.print_xml2_comment(comment)),
+			OutputFormat::Jsonl => self.try_print(|data| data.print_jsonl_comment(comment)),
+		}
+	}
+
+	fn processing_instruction(&mut self, target: &str, data: &str) {
+		self.flush_text();
+		if !self.content_visible() {
+			return;
+		}
+
+		match self.opts().output_format {
+			OutputFormat::Path => self.try_print(|parser_data| parser_data.print_processing_instruction(target, data)),
+			OutputFormat::Xml2 => self.try_print(|parser_data| parser_data.print_xml2_processing_instruction(target, data)),
+			OutputFormat::Jsonl => self.try_print(|parser_data| parser_data.print_jsonl_processing_instruction(target, data)),
+		}
+	}
+
+	// The path formats print the path of the element the first time it is found invalid,
+	// JSONL prints a record for every error
+	fn invalid(&mut self, message: &str) {
+		if !self.opts().invalid_paths || self.in_skipped_element() {
+			return;
+		}
+
+		match self.opts().output_format {
+			OutputFormat::Jsonl => self.try_print(|data| data.print_jsonl_invalid(message)),
+			_ => self.try_print(|data| data.print_last_tag()),
+		}
+	}
+}
+
+impl<'a, W: Write> ParserData<'a, W> {
+	// Prints the text buffered since the last event, which libxml2 may have split over
+	// several callbacks, or the part of it that reached `text_buffer_size`. It's normalized
+	// as a whole and printed at the location where it starts rather than the current one.
+	pub(crate) fn flush_text(&mut self) {
+		let (text, location) = match self.take_text() {
+			Some(text) => text,
+			None => return,
+		};
+
+		let text = normalize_text(&text, self.opts().text_normalization);
+		if text.is_empty() {
+			return;
+		}
+
+		let current = self.current_location();
+		self.set_location(location);
+		self.print_text(&text);
+		self.set_location(current);
+	}
+
+	fn print_text(&mut self, chars: &str) {
+		match self.opts().output_format {
+			OutputFormat::Path => self.try_print(|data| data.print_characters(chars)),
+			OutputFormat::Xml2 => self.try_print(|data| data.print_xml2_characters(chars)),
+			OutputFormat::Jsonl => self.try_print(|data| data.print_jsonl_characters(chars)),
+		}
+	}
+
+	// Applies the include and exclude patterns. Returns whether the element is printed.
+	fn filter_start_element(&mut self, name: &QName) -> bool {
+		let opts = self.opts();
+		if opts.include.is_empty() && opts.exclude.is_empty() && !opts.invalid_paths {
+			return true;
+		}
+
+		let name = format_name(name, opts.name_format);
+		match self.element_visibility(&name) {
+			Visibility::Visible => true,
+			Visibility::Hidden => {
+				// Keep the element in the path of its descendants without printing it,
+				// its path is printed if it is found invalid.
+				let mut tag = XmlTag::from(&name, !opts.invalid_paths);
+				tag.set_visible(false);
+				self.push_tag(tag);
+				false
+			},
+			Visibility::Excluded => {
+				self.skip_element();
+				false
+			},
+		}
+	}
+
+	fn print_start_element(&mut self, name: &QName, attrs: &[Attribute], namespaces: &[Namespace]) -> Result<(), std::io::Error> {
+		let opts = self.opts();
+		self.print_last_tag()?;
+
+		let name = format_name(name, opts.name_format);
+		let repeated = self.take_closed_sibling().as_deref() == Some(&*name);
+		self.push_tag(XmlTag::from(&name, false));
+
+		// Namespace declarations are printed as attributes unless they get their own lines
+		let inline_namespaces = if opts.namespace_lines { &[][..] } else { namespaces };
+
+		// The text of two consecutive siblings with the same name would look like the
+		// text of a single element, a bare path marks the start of the second one
+		if repeated && attrs.is_empty() && inline_namespaces.is_empty() {
+			self.print_last_tag()?;
+		}
+		if !attrs.is_empty() || !inline_namespaces.is_empty() {
+			let (opts, tags, write_buf) = self.opts_tags_and_buf_mut();
+			write!(write_buf, "{}@[", tags)?;
+			for (i, ns) in inline_namespaces.iter().enumerate() {
+				if i != 0 {
+					write!(write_buf, ",")?;
+				}
+
+				write!(write_buf, "{}=", ns)?;
+				print_string(write_buf, ns.uri, opts)?;
+			}
+
+			for (i, attr) in attrs.iter().enumerate() {
+				if i != 0 || !inline_namespaces.is_empty() {
+					write!(write_buf, ",")?;
+				}
+
+				write!(write_buf, "{}=", format_name(&attr.name, opts.name_format))?;
+				print_string(write_buf, attr.value, opts)?;
+			}
+			writeln!(write_buf, "]")?;
+
+			self.last_tag_mut().unwrap().set_printed(true);
+		}
+
+		if !opts.namespace_lines || namespaces.is_empty() {
+			return Ok(());
+		}
+
+		// The element's own line always comes first so that `unflatten` can tell the
+		// declarations of consecutive siblings apart
+		self.print_last_tag()?;
+		let (opts, tags, write_buf) = self.opts_tags_and_buf_mut();
+		for ns in namespaces {
+			write!(write_buf, "{}/{}=[", tags, ns)?;
+			print_string(write_buf, ns.uri, opts)?;
+			writeln!(write_buf, "]")?;
+		}
+
+		Ok(())
+	}
+
+	fn print_end_element(&mut self, name: &QName) -> Result<(), std::io::Error> {
+		let name = format_name(name, self.opts().name_format);
+		let last = match self.last_tag() {
+			Some(last) => last,
+			None => return Ok(()),
+		};
+
+		if last.name() != name {
+			return Ok(());
+		}
+
+		self.print_last_tag()?;
+		self.pop_tag();
+		self.set_closed_sibling(Some(name.into_owned()));
+		Ok(())
+	}
+
+	// Text and CDATA sections have no path outside of an element
+	fn print_characters(&mut self, chars: &str) -> Result<(), std::io::Error> {
+		if self.tags_is_empty() || (!self.opts().keep_all_whitespace && is_only_whitespace(chars)) {
+			return Ok(());
+		}
+
+		let (opts, tags, write_buf) = self.opts_tags_and_buf_mut();
+		write!(write_buf, "{}=\"", tags)?;
+		print_string(write_buf, chars, opts)?;
+		writeln!(write_buf, "\"")?;
+
+		self.last_tag_mut().unwrap().set_printed(true);
+		self.set_closed_sibling(None);
+		Ok(())
+	}
+
+	fn print_cdata(&mut self, data: &str) -> Result<(), std::io::Error> {
+		if self.tags_is_empty() {
+			return Ok(());
+		}
+
+		let (opts, tags, write_buf) = self.opts_tags_and_buf_mut();
+		write!(write_buf, "{}/![CDATA[", tags)?;
+		print_string(write_buf, data, opts)?;
+		writeln!(write_buf, "]]")?;
+
+		self.last_tag_mut().unwrap().set_printed(true);
+		self.set_closed_sibling(None);
+		Ok(())
+	}
+
+	// The line of the element comes first, otherwise `unflatten` would take it for the
+	// start of a sibling once the comment has opened the element
+	fn print_comment(&mut self, comment: &str) -> Result<(), std::io::Error> {
+		self.print_last_tag()?;
+		let (opts, tags, write_buf) = self.opts_tags_and_buf_mut();
+		write!(write_buf, "{}/![", tags)?;
+		print_string(write_buf, comment, opts)?;
+		writeln!(write_buf, "]")?;
+
+		self.set_closed_sibling(None);
+		Ok(())
+	}
+
+	fn print_processing_instruction(&mut self, target: &str, data: &str) -> Result<(), std::io::Error> {
+		self.print_last_tag()?;
+		let (opts, tags, write_buf) = self.opts_tags_and_buf_mut();
+		write!(write_buf, "{}/{}?[", tags, target)?;
+		print_string(write_buf, data, opts)?;
+		writeln!(write_buf, "]")?;
+
+		self.set_closed_sibling(None);
+		Ok(())
+	}
+
+	// The markers around the output of a file let the output of several files be split
+	pub(crate) fn print_file_start(&mut self, filename: &str) -> Result<(), std::io::Error> {
+		match self.opts().output_format {
+			OutputFormat::Jsonl => self.print_jsonl_file_marker("file-start", filename),
+			_ => writeln!(self.opts_tags_and_buf_mut().2, "==> {} <==", filename),
+		}
+	}
+
+	pub(crate) fn print_file_end(&mut self, filename: &str) -> Result<(), std::io::Error> {
+		match self.opts().output_format {
+			OutputFormat::Jsonl => self.print_jsonl_file_marker("file-end", filename),
+			_ => writeln!(self.opts_tags_and_buf_mut().2, "<== {} ==>", filename),
+		}
+	}
+}
+
+fn format_name<'a>(name: &QName<'a>, format: NameFormat) -> Cow<'a, str> {
+	match (format, name.prefix, name.uri) {
+		(NameFormat::AsIs, Some(_), _) => Cow::Owned(name.to_string()),
+		(NameFormat::Clark, _, Some(uri)) => Cow::Owned(format!("{{{}}}{}", uri, name.local_name)),
+		_ => Cow::Borrowed(name.local_name),
+	}
+}
+
+#[inline(always)]
+fn is_only_whitespace(string: &str) -> bool {
+	string.trim().is_empty()
+}
+
+// The whitespace of XML, which is all that `normalize-space()` strips
+fn is_xml_whitespace(c: char) -> bool {
+	matches!(c, ' ' | '\t' | '\n' | '\r')
+}
+
+fn normalize_text(text: &str, normalization: TextNormalization) -> Cow<'_, str> {
+	match normalization {
+		TextNormalization::None => Cow::Borrowed(text),
+		TextNormalization::Trim => Cow::Borrowed(text.trim_matches(is_xml_whitespace)),
+		TextNormalization::NormalizeSpace => {
+			let mut normalized = String::with_capacity(text.len());
+			for word in text.split(is_xml_whitespace).filter(|word| !word.is_empty()) {
+				if !normalized.is_empty() {
+					normalized.push(' ');
+				}
+				normalized.push_str(word);
+			}
+
+			Cow::Owned(normalized)
+		},
+	}
+}
+
+#[inline(always)]
+pub fn print_string<W: Write>(write_buf: &mut W, string: &str, opts: &ProgramOpts) -> Result<(), std::io::Error> {
+	if !opts.map_whitespace && !opts.compress_whitespace && opts.escape_mode == EscapeMode::None {
+		return write_buf.write_all(string.as_bytes());
+	}
+
+	// Shared buffer to translate a char to byte slice
+	let mut buf = [0; 4];
+
+	// NOTE: The most efficient way for transliterating whitespace characters to UTF-8
+	//       characters is to do it right before printing the character. Doing this is
+	//       much cheaper than allocating a new buffer and transliterating into it.
+	//       Another approach I thought of is to modify the string right before storing
+	//       it into memory, but it would require modifying the libxml2 library and that
+	//       is too much of a hassle.
+	let (space_char, tab_char, newline_char) = if opts.map_whitespace {
+		(opts.space_map, opts.tab_map, opts.newline_map)
+	} else {
+		(' ', '\t', '\n')
+	};
+
+	// Map whitespace without compressing
+	if !opts.compress_whitespace {
+		for char in string.chars() {
+			transliterate_and_print(write_buf, char, space_char, tab_char, newline_char, opts, &mut buf)?;
+		}
+
+		return Ok(());
+	}
+
+	let mut space_count = 0;
+	for char in string.chars() {
+		if char != ' ' {
+			if space_count < opts.compress_level {
+			    for _ in 0..space_count {
+			        write_buf.write_all(char_to_bytes(space_char, &mut buf))?;
+			    }
+			} else {
+				write_buf.write_all(char_to_bytes(tab_char, &mut buf))?;
+			}
+			space_count = 0;
+
+			transliterate_and_print(write_buf, char, space_char, tab_char, newline_char, opts, &mut buf)?;
+			continue;
+		}
+
+		space_count += 1;
+		if space_count == opts.compress_level {
+			write_buf.write_all(char_to_bytes(tab_char, &mut buf))?;
+			space_count = 0;
+		}
+	}
+
+	// Print any spaces that weren't printed
+	for _ in 0..space_count {
+	    write_buf.write_all(char_to_bytes(space_char, &mut buf))?;
+	}
+
+	Ok(())
+}
+
+// Shared by the tests of `unflatten` and `unmap`, which read the printed values back
+#[cfg(test)]
+pub(crate) mod test_values {
+	use super::print_string;
+
+	use crate::config::ProgramOpts;
+
+	// Whitespace runs, the characters whitespace is mapped to and the ones that are escaped
+	pub(crate) const VALUES: &[&str] = &[
+		"",
+		"plain",
+		"a     b  c\td\n\ne",
+		"    leading and trailing    ",
+		"line\nbreak\ttab\rreturn",
+		"mapped · ↹ ↵ chars",
+		"back\\slash \\· \\t \\\\ \\n",
+		"escapes \" ] , = a,b=c]",
+		"amp & lt < gt > &amp; &#x41; &#x20; &#xb7;",
+		"\\u{41} \\",
+		"café ✓ 😀 \u{7}",
+	];
+
+	pub(crate) fn printed(value: &str, opts: &ProgramOpts) -> String {
+		let mut out = Vec::new();
+		print_string(&mut out, value, opts).unwrap();
+		String::from_utf8(out).unwrap()
+	}
+}
+
+// Mapped whitespace is printed as is, every other character is escaped. The output
+// is kept reversible by `unmap`: compressed spaces use the same character as tabs, so
+// tabs are escaped instead, and the characters whitespace is mapped to are escaped
+// where they appear in the text. Without an escape mode a backslash escapes them.
+#[inline(always)]
+fn transliterate_and_print<W: Write>(write_buf: &mut W, c: char, space: char, tab: char, newline: char, opts: &ProgramOpts, buf: &mut [u8]) -> Result<(), std::io::Error> {
+	if c == '\t' && opts.compress_whitespace {
+		return match opts.escape_mode {
+			EscapeMode::None => write_buf.write_all(b"\\t"),
+			_ => escape_and_print(write_buf, c, opts, buf),
+		};
+	}
+
+	let mapped = match c {
+		' '  =>  space,
+		'\t' =>  tab,
+		'\n' =>  newline,
+		   _ =>  c,
+	};
+
+	if mapped != c {
+		return write_buf.write_all(char_to_bytes(mapped, buf));
+	}
+
+	if opts.map_whitespace && (c == space || c == tab || c == newline) {
+		return match opts.escape_mode {
+			EscapeMode::Xml => write!(write_buf, "&#x{:x};", c as u32),
+			_ => {
+				write_buf.write_all(b"\\")?;
+				write_buf.write_all(char_to_bytes(c, buf))
+			},
+		};
+	}
+
+	if c == '\\' && opts.escape_mode == EscapeMode::None {
+		return write_buf.write_all(b"\\\\");
+	}
+
+	escape_and_print(write_buf, c, opts, buf)
+}
+
+// Escapes the characters that are part of the line grammar so that every value
+// fits on a single line and can be parsed back unambiguously
+#[inline(always)]
+pub(crate) fn escape_and_print<W: Write>(write_buf: &mut W, c: char, opts: &ProgramOpts, buf: &mut [u8]) -> Result<(), std::io::Error> {
+	let escape_non_ascii = opts.escape_non_ascii && (!c.is_ascii() || c.is_ascii_control());
+	let escaped = match opts.escape_mode {
+		EscapeMode::None => None,
+		EscapeMode::C => match c {
+			'\\' => Some("\\\\"),
+			'"'  => Some("\\\""),
+			'\n' => Some("\\n"),
+			'\t' => Some("\\t"),
+			'\r' => Some("\\r"),
+			']'  => Some("\\]"),
+			','  => Some("\\,"),
+			'='  => Some("\\="),
+			_ if escape_non_ascii => return write!(write_buf, "\\u{{{:x}}}", c as u32),
+			_ => None,
+		},
+		EscapeMode::Xml => match c {
+			'&'  => Some("&amp;"),
+			'<'  => Some("&lt;"),
+			'>'  => Some("&gt;"),
+			'"'  => Some("&quot;"),
+			'\n' => Some("&#10;"),
+			'\t' => Some("&#9;"),
+			'\r' => Some("&#13;"),
+			']'  => Some("&#93;"),
+			','  => Some("&#44;"),
+			'='  => Some("&#61;"),
+			_ if escape_non_ascii => return write!(write_buf, "&#x{:x};", c as u32),
+			_ => None,
+		},
+	};
+
+	match escaped {
+		Some(escaped) => write_buf.write_all(escaped.as_bytes()),
+		None => write_buf.write_all(char_to_bytes(c, buf)),
+	}
+}
+
+#[inline(always)]
+fn char_to_bytes(c: char, buf: &mut [u8]) -> &[u8] {
+	c.encode_utf8(buf).as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::normalize_text;
+
+	use crate::config::{ ProgramOpts, TextNormalization };
+	use crate::handler::QName;
+	use crate::parser_data::ParserData;
+
+	use std::borrow::Cow;
+
+	fn trim(text: &str) -> Cow<'_, str> {
+		normalize_text(text, TextNormalization::Trim)
+	}
+
+	fn normalize_space(text: &str) -> Cow<'_, str> {
+		normalize_text(text, TextNormalization::NormalizeSpace)
+	}
+
+	#[test]
+	fn unchanged_without_normalization() {
+		assert_eq!(normalize_text(" \ta  b\n", TextNormalization::None), " \ta  b\n");
+	}
+
+	#[test]
+	fn trimmed_runs() {
+		assert!(matches!(trim("\r\n\t a  b \n\n"), Cow::Borrowed("a  b")));
+		assert_eq!(trim("a"), "a");
+		assert_eq!(trim(" \t\r\n "), "");
+		assert_eq!(trim(""), "");
+	}
+
+	#[test]
+	fn only_xml_whitespace_trimmed() {
+		assert_eq!(trim("\u{a0}a\u{2003}"), "\u{a0}a\u{2003}");
+		assert_eq!(normalize_space("a\u{a0}\u{a0}b"), "a\u{a0}\u{a0}b");
+	}
+
+	#[test]
+	fn collapsed_runs() {
+		assert_eq!(normalize_space("  a \t\r\n b  c\n"), "a b c");
+		assert_eq!(normalize_space("a"), "a");
+		assert_eq!(normalize_space(" \t\r\n "), "");
+		assert_eq!(normalize_space(""), "");
+	}
+
+	#[test]
+	fn events_outside_elements() {
+		let opts = ProgramOpts::default();
+		let name = QName { local_name: "a", prefix: None, uri: None };
+		let mut data = ParserData::with_capacity(4, &opts, Vec::new());
+		data.print_characters("x").unwrap();
+		data.print_cdata("y").unwrap();
+		data.print_end_element(&name).unwrap();
+		data.print_xml2_end_element(&name).unwrap();
+
+		assert!(data.finish().unwrap().is_empty());
+	}
+}
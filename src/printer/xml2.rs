@@ -0,0 +1,134 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::handler::{ Attribute, Namespace, QName };
+
+use crate::parser_data::{ LinePrefix, ParserData, XmlTag };
+
+use crate::config::{ EscapeMode, ProgramOpts };
+
+use super::{ format_name, is_only_whitespace, print_string };
+
+use std::fmt::Display;
+use std::io::Write;
+
+// Unlike the path format only leaf elements get a line of their own. Every other
+// element is implied by the paths of its descendants.
+impl<'a, W: Write> ParserData<'a, W> {
+	pub(super) fn print_xml2_start_element(&mut self, name: &QName, attrs: &[Attribute], namespaces: &[Namespace]) -> Result<(), std::io::Error> {
+		let name = format_name(name, self.opts().name_format);
+		if let Some(parent) = self.last_tag_mut() {
+			parent.set_printed(true);
+		}
+
+		let repeated = self.take_closed_sibling().as_deref() == Some(&*name);
+		self.push_tag(XmlTag::from(&name, false));
+
+		// The lines of two consecutive siblings with the same name would look like the
+		// lines of a single element, a bare path marks the start of the second one
+		if repeated {
+			self.print_last_tag()?;
+		}
+
+		if attrs.is_empty() && namespaces.is_empty() {
+			return Ok(());
+		}
+
+		let (opts, tags, write_buf) = self.opts_tags_and_buf_mut();
+		for ns in namespaces {
+			print_lines(write_buf, &tags, "/@", &ns, ns.uri, opts)?;
+		}
+
+		for attr in attrs {
+			print_lines(write_buf, &tags, "/@", &format_name(&attr.name, opts.name_format), attr.value, opts)?;
+		}
+
+		self.last_tag_mut().unwrap().set_printed(true);
+		Ok(())
+	}
+
+	pub(super) fn print_xml2_end_element(&mut self, name: &QName) -> Result<(), std::io::Error> {
+		let name = format_name(name, self.opts().name_format);
+		let last = match self.last_tag() {
+			Some(last) => last,
+			None => return Ok(()),
+		};
+
+		if last.name() != name {
+			return Ok(());
+		}
+
+		self.print_last_tag()?;
+		self.pop_tag();
+		self.set_closed_sibling(Some(name.into_owned()));
+		Ok(())
+	}
+
+	pub(super) fn print_xml2_characters(&mut self, chars: &str) -> Result<(), std::io::Error> {
+		if !self.opts().keep_all_whitespace && is_only_whitespace(chars) {
+			return Ok(());
+		}
+
+		let (opts, tags, write_buf) = self.opts_tags_and_buf_mut();
+		print_lines(write_buf, &tags, "", &"", chars, opts)?;
+
+		self.set_content_printed();
+		Ok(())
+	}
+
+	pub(super) fn print_xml2_comment(&mut self, comment: &str) -> Result<(), std::io::Error> {
+		let (opts, tags, write_buf) = self.opts_tags_and_buf_mut();
+		print_lines(write_buf, &tags, "/!", &"", comment, opts)?;
+
+		self.set_content_printed();
+		Ok(())
+	}
+
+	pub(super) fn print_xml2_processing_instruction(&mut self, target: &str, data: &str) -> Result<(), std::io::Error> {
+		let (opts, tags, write_buf) = self.opts_tags_and_buf_mut();
+		print_lines(write_buf, &tags, "/?", &target, data, opts)?;
+
+		self.set_content_printed();
+		Ok(())
+	}
+
+	fn set_content_printed(&mut self) {
+		self.set_closed_sibling(None);
+		if let Some(tag) = self.last_tag_mut() {
+			tag.set_printed(true);
+		}
+	}
+}
+
+// Prints `{tags}{marker}{name}=value`. xml2 prints every line of a value on a line of
+// its own, unless the newlines are mapped to another character or escaped.
+fn print_lines<W: Write>(write_buf: &mut W, tags: &LinePrefix, marker: &str, name: &dyn Display, value: &str, opts: &ProgramOpts) -> Result<(), std::io::Error> {
+	if opts.map_whitespace || opts.escape_mode != EscapeMode::None {
+		write!(write_buf, "{}{}{}=", tags, marker, name)?;
+		print_string(write_buf, value, opts)?;
+		return writeln!(write_buf);
+	}
+
+	for line in value.split('\n') {
+		write!(write_buf, "{}{}{}=", tags, marker, name)?;
+		print_string(write_buf, line, opts)?;
+		writeln!(write_buf)?;
+	}
+
+	Ok(())
+}
@@ -19,19 +19,76 @@
 use crate::bindings::{ self, xmlChar };
 use crate::bindings::xmlSAXHandler;
 use crate::bindings::xmlSAXHandlerPtr;
+use crate::bindings::xmlParserCtxtPtr;
 
-use crate::ptr_conversions::str_from_xmlchar;
-use crate::ptr_conversions::str_from_xmlchar_with_null;
-use crate::ptr_conversions::slice_from_ptr_with_null;
+use crate::ptr_conversions::bytes_from_xmlchar;
+use crate::ptr_conversions::bytes_from_xmlchar_with_null;
+use crate::ptr_conversions::option_bytes_from_xmlchar_with_null;
+use crate::ptr_conversions::slice_from_ptr;
 
-use crate::parser_data::ParserData;
-use crate::parser_data::XmlTag;
+use crate::handler::{ Attribute, Namespace, QName, XmlEventHandler };
 
-use crate::config::ProgramOpts;
+use crate::error::{ Diagnostic, Severity };
 
-use std::io::Write;
+use crate::locator::Locator;
 
-use cty::c_void;
+use crate::config::{ InputFormat, InvalidUtf8, ProgramOpts };
+
+use crate::schema::{ RelaxNgSchema, XmlSchema };
+
+use std::borrow::Cow;
+use std::cell::{ Cell, RefCell, RefMut };
+use std::ffi::{ CStr, CString };
+use std::fmt::Write;
+use std::io::{ ErrorKind, Read };
+use std::sync::Once;
+
+use cty::{ c_char, c_int, c_ulong, c_void };
+
+// Size of the chunks read from a stream and pushed to libxml2
+const CHUNK_SIZE: usize = 4096;
+
+static INIT: Once = Once::new();
+
+// The user data passed to every callback. It is only borrowed immutably since libxml2
+// reports errors to `sax_structured_error` while the callbacks call into it.
+struct SaxContext<'a> {
+	handler: RefCell<&'a mut dyn XmlEventHandler>,
+	diagnostics: RefCell<Vec<Diagnostic>>,
+	ctxt: xmlParserCtxtPtr,
+	invalid_utf8: InvalidUtf8,
+	// Set once the parser is stopped because of an invalid string or an exceeded limit,
+	// the events still reported by libxml2 are ignored
+	stopped: Cell<bool>,
+	limits: Limits,
+	depth: Cell<usize>,
+	// The size of the text received since the last element, comment or PI
+	text_size: Cell<usize>,
+	// Whether the events are also passed to the SAX2 callbacks of libxml2 to build the
+	// tree that is validated
+	build_tree: bool,
+	// Whether the whole tree is kept since it is validated against a DTD, otherwise the
+	// elements are freed once the RELAX NG schema has checked them
+	keep_tree: bool,
+	xsd: Option<Xsd>,
+	rng: Option<Rng>,
+	// Set once the whole document is parsed, the validity errors found afterwards are not
+	// about the current element
+	parsed: Cell<bool>,
+}
+
+struct Limits {
+	max_depth: Option<usize>,
+	max_attributes: Option<usize>,
+	max_text_size: Option<usize>,
+}
+
+// The strings of a `QName` checked to be valid UTF-8
+struct Name<'a> {
+	local_name: Cow<'a, str>,
+	prefix: Option<Cow<'a, str>>,
+	uri: Option<Cow<'a, str>>,
+}
 
 pub fn default_sax_handler() -> xmlSAXHandler {
 	xmlSAXHandler {
@@ -70,189 +127,902 @@ pub fn default_sax_handler() -> xmlSAXHandler {
 	}
 }
 
-pub fn init_sax_handler(sax: xmlSAXHandlerPtr) {
+pub fn init_sax_handler(sax: xmlSAXHandlerPtr, format: InputFormat, opts: &ProgramOpts) {
 	unsafe {
+		// The HTML parser only calls the SAX1 element callbacks, the XML parser only
+		// calls the namespace aware ones when both are set
+		(*sax).startElementNs = Some(sax_start_element_ns);
+		(*sax).endElementNs = Some(sax_end_element_ns);
 		(*sax).startElement = Some(sax_start_element);
 		(*sax).endElement = Some(sax_end_element);
 		(*sax).characters = Some(sax_characters);
 		(*sax).ignorableWhitespace = Some(sax_characters);
+		// The contents of script and style elements are text in HTML rather than CDATA
+		(*sax).cdataBlock = match format {
+			InputFormat::Xml => Some(sax_cdata_block),
+			InputFormat::Html => None,
+		};
 		(*sax).processingInstruction = Some(sax_processing_instruction);
 		(*sax).comment = Some(sax_comment);
 		(*sax).warning = Some(bindings::sax_warning);
 		(*sax).error = Some(bindings::sax_error);
 		(*sax).fatalError = Some(bindings::sax_fatal_error);
-		(*sax).initialized = 1;
+		(*sax).serror = Some(sax_structured_error);
+		(*sax).initialized = bindings::XML_SAX2_MAGIC;
+	}
+
+	let dtd = validates_dtd(format, opts);
+	if dtd || (format == InputFormat::Xml && opts.rng.is_some()) {
+		init_tree_sax_handler(sax, dtd);
+	}
+
+	INIT.call_once(|| unsafe { bindings::sax_set_report_func(Some(sax_report)) });
+}
+
+// libxml2 validates the tree it builds with its own SAX2 callbacks. The element and
+// text callbacks pass their events on to them, and the declarations of the DTD are
+// recorded by them when it is validated.
+fn init_tree_sax_handler(sax: xmlSAXHandlerPtr, dtd: bool) {
+	unsafe {
+		(*sax).startDocument = Some(sax_start_document);
+		(*sax).endDocument = Some(sax_end_document);
+		if !dtd {
+			return;
+		}
+
+		(*sax).internalSubset = Some(sax_internal_subset);
+		(*sax).externalSubset = Some(sax_external_subset);
+		(*sax).isStandalone = Some(sax_is_standalone);
+		(*sax).hasInternalSubset = Some(sax_has_internal_subset);
+		(*sax).hasExternalSubset = Some(sax_has_external_subset);
+		(*sax).resolveEntity = Some(sax_resolve_entity);
+		(*sax).getEntity = Some(sax_get_entity);
+		(*sax).getParameterEntity = Some(sax_get_parameter_entity);
+		(*sax).entityDecl = Some(sax_entity_decl);
+		(*sax).notationDecl = Some(sax_notation_decl);
+		(*sax).attributeDecl = Some(sax_attribute_decl);
+		(*sax).elementDecl = Some(sax_element_decl);
+		(*sax).unparsedEntityDecl = Some(sax_unparsed_entity_decl);
 	}
 }
 
-pub fn sax_user_parse_file(sax: xmlSAXHandlerPtr, data_ptr: *mut c_void, file: std::ffi::CString) {
-	unsafe { bindings::xmlSAXUserParseFile(sax, data_ptr, file.into_raw()); }
+// DTDs are only validated in XML documents
+fn validates_dtd(format: InputFormat, opts: &ProgramOpts) -> bool {
+	format == InputFormat::Xml && (opts.validate || opts.dtd.is_some())
 }
 
-extern fn sax_start_element(user_data_ptr: *mut c_void, name: *const xmlChar, attrs: *mut *const xmlChar) {
-	let user_data = deref_mut_void_ptr::<ParserData>(user_data_ptr);
+// Parses the document incrementally using libxml2's push parser so that only a
+// single chunk of the stream is held in memory at a time, unless it is validated
+// against a DTD. `filename` is used to resolve the relative URIs in the document.
+pub fn sax_user_parse_reader(sax: xmlSAXHandlerPtr, format: InputFormat, opts: &ProgramOpts, handler: &mut dyn XmlEventHandler, reader: &mut dyn Read, filename: Option<&CStr>) -> Result<Vec<Diagnostic>, std::io::Error> {
+	let validate = validates_dtd(format, opts);
+	let dtd = opts.dtd.as_ref().filter(|_| validate);
+
+	// Schemas are only validated in XML documents
+	let xsd = match &opts.xsd {
+		Some(schema) if format == InputFormat::Xml => Some(Xsd::new(schema)?),
+		_ => None,
+	};
+	let rng = match &opts.rng {
+		Some(schema) if format == InputFormat::Xml => Some(Rng::new(schema)?),
+		_ => None,
+	};
+
+	let mut context = SaxContext {
+		handler: RefCell::new(handler),
+		diagnostics: RefCell::new(Vec::new()),
+		ctxt: std::ptr::null_mut(),
+		invalid_utf8: opts.invalid_utf8,
+		stopped: Cell::new(false),
+		limits: Limits {
+			max_depth: opts.max_depth,
+			max_attributes: opts.max_attributes,
+			max_text_size: opts.max_text_size,
+		},
+		depth: Cell::new(0),
+		text_size: Cell::new(0),
+		build_tree: validate || rng.is_some(),
+		keep_tree: validate,
+		xsd,
+		rng,
+		parsed: Cell::new(false),
+	};
+	let context_ptr = &mut context as *mut SaxContext;
+	let data_ptr = context_ptr as *mut c_void;
+	let mut chunk = [0; CHUNK_SIZE];
+
+	// libxml2 needs the first 4 bytes of the document to detect its encoding
+	let mut len = 0;
+	while len < 4 {
+		match read_chunk(reader, &mut chunk[len..])? {
+			0 => break,
+			n => len += n,
+		}
+	}
+
+	let ctxt = unsafe {
+		let filename = filename.map_or(std::ptr::null(), |f| f.as_ptr());
+		let chunk = chunk.as_ptr() as *const c_char;
+		match format {
+			InputFormat::Xml => bindings::xmlCreatePushParserCtxt(sax, data_ptr, chunk, len as c_int, filename),
+			InputFormat::Html => bindings::htmlCreatePushParserCtxt(sax, data_ptr, chunk, len as c_int, filename, bindings::xmlCharEncoding_XML_CHAR_ENCODING_NONE),
+		}
+	};
+
+	if ctxt.is_null() {
+		return Err(std::io::Error::new(ErrorKind::Other, "Failed to create the push parser"));
+	}
 
-	user_data.print_last_tag();
+	// The callbacks need the context to locate the events in the document, and the
+	// errors reported with the parser context as user data need to find the context
+	unsafe {
+		(*context_ptr).ctxt = ctxt;
+		(*ctxt)._private = data_ptr;
+		if let Some(xsd) = &(*context_ptr).xsd {
+			xsd.report_errors_to(data_ptr);
+		}
 
-	let name = str_from_xmlchar_with_null(name);
-	user_data.push_tag(XmlTag::from(name, false));
+		if let Some(rng) = &(*context_ptr).rng {
+			rng.report_errors_to(data_ptr);
+		}
+	}
 
-	if attrs.is_null() {
-		return;
+	if let Some(encoding) = &opts.encoding {
+		if let Err(e) = switch_encoding(ctxt, encoding) {
+			unsafe { free_validators(context_ptr) };
+			free_parser_ctxt(ctxt, format);
+			return Err(e);
+		}
 	}
 
-	let attrs = slice_from_ptr_with_null(attrs);
-	let attrs: Vec<&str> = attrs.iter().map(|e| str_from_xmlchar_with_null(*e)).collect();
+	set_parser_options(ctxt, format, opts);
+
+	let result = loop {
+		let len = match read_chunk(reader, &mut chunk) {
+			Ok(len) => len,
+			Err(e) => break Err(e),
+		};
+
+		let terminate = (len == 0) as c_int;
+		let chunk = chunk.as_ptr() as *const c_char;
+		unsafe {
+			match format {
+				InputFormat::Xml => bindings::xmlParseChunk(ctxt, chunk, len as c_int, terminate),
+				InputFormat::Html => bindings::htmlParseChunk(ctxt, chunk, len as c_int, terminate),
+			};
+		}
+
+		// The rest of the input isn't read once the parser gave up on the document, so
+		// that a document stopped for exceeding a limit isn't decompressed further
+		if len == 0 || unsafe { (*context_ptr).stopped.get() || (*ctxt).disableSAX != 0 } {
+			break Ok(());
+		}
+	};
 
-	let (opts, tags, write_buf) = user_data.opts_tags_and_buf_mut();
-	write!(write_buf, "{}@[", tags).unwrap();
-	for i in (0..attrs.len()).step_by(2) {
-		write!(write_buf, "{}=", attrs[i]).unwrap();
-		print_string(write_buf, attrs[i + 1], opts).unwrap();
+	unsafe { (*context_ptr).parsed.set(true) };
+
+	// The errors are reported to the callbacks like those found while parsing
+	if let Some(dtd) = dtd {
+		unsafe {
+			let doc = (*ctxt).myDoc;
+			if result.is_ok() && !doc.is_null() && (*ctxt).wellFormed != 0 && !(*context_ptr).stopped.get() {
+				let (dtd, _guard) = dtd.lock();
+				bindings::xmlValidateDtd(&mut (*ctxt).vctxt, doc, dtd);
+			}
+		}
+	}
 
-		if i != (attrs.len() - 2) {
-			write!(write_buf, ",").unwrap();
+	unsafe {
+		if !(*ctxt).myDoc.is_null() {
+			bindings::xmlFreeDoc((*ctxt).myDoc);
 		}
 	}
-	writeln!(write_buf, "]").unwrap();
 
-	user_data.last_tag_mut().unwrap().set_printed(true);
+	unsafe { free_validators(context_ptr) };
+	free_parser_ctxt(ctxt, format);
+	result.map(|_| context.diagnostics.into_inner())
 }
 
-extern fn sax_end_element(user_data_ptr: *mut c_void, name: *const xmlChar) {
-	let user_data = deref_mut_void_ptr::<ParserData>(user_data_ptr);
-	let name = str_from_xmlchar_with_null(name);
+// The validators are unplugged and freed while the parser context and the rest of the
+// SaxContext are still alive, so that no callback runs against a half-dropped context
+unsafe fn free_validators(context_ptr: *mut SaxContext) {
+	drop((*context_ptr).xsd.take());
+	drop((*context_ptr).rng.take());
+}
+
+// Checks the document against an XML schema while it is parsed. The callbacks pass
+// their events on to the ones of the validator.
+struct Xsd {
+	vctxt: bindings::xmlSchemaValidCtxtPtr,
+	plug: bindings::xmlSchemaSAXPlugPtr,
+	// The callbacks of the validator and their user data, boxed since they are written
+	// again when it is unplugged
+	sax: Box<(xmlSAXHandlerPtr, *mut c_void)>,
+}
+
+impl Xsd {
+	fn new(schema: &XmlSchema) -> Result<Self, std::io::Error> {
+		let failed = || std::io::Error::new(ErrorKind::Other, "Failed to create the XML schema validation context");
+
+		// Without a SAX handler to wrap, the plug gives the callbacks of the validator
+		let mut xsd = Xsd {
+			vctxt: unsafe { bindings::xmlSchemaNewValidCtxt(schema.as_ptr()) },
+			plug: std::ptr::null_mut(),
+			sax: Box::new((std::ptr::null_mut(), std::ptr::null_mut())),
+		};
+
+		if !xsd.vctxt.is_null() {
+			xsd.plug = unsafe { bindings::xmlSchemaSAXPlug(xsd.vctxt, &mut xsd.sax.0, &mut xsd.sax.1) };
+		}
+
+		if xsd.plug.is_null() {
+			return Err(failed());
+		}
+
+		Ok(xsd)
+	}
+
+	fn report_errors_to(&self, data_ptr: *mut c_void) {
+		unsafe {
+			bindings::xmlSchemaSetValidStructuredErrors(self.vctxt, Some(sax_structured_error), data_ptr);
+			bindings::xmlSchemaValidateSetLocator(self.vctxt, Some(xsd_locator), data_ptr);
+		}
+	}
+
+	#[inline(always)]
+	fn sax(&self) -> &xmlSAXHandler {
+		unsafe { &*self.sax.0 }
+	}
+
+	#[inline(always)]
+	fn user_data(&self) -> *mut c_void {
+		self.sax.1
+	}
+}
+
+impl Drop for Xsd {
+	fn drop(&mut self) {
+		unsafe {
+			if !self.plug.is_null() {
+				bindings::xmlSchemaSAXUnplug(self.plug);
+			}
+
+			bindings::xmlSchemaFreeValidCtxt(self.vctxt);
+		}
+	}
+}
+
+// Checks the document against a RELAX NG schema while it is parsed. The elements of
+// the tree are checked as they are built. Those whose content can only be checked
+// as a whole are checked once they end.
+struct Rng {
+	vctxt: bindings::xmlRelaxNGValidCtxtPtr,
+	// The element checked as a whole, its descendants are not pushed to the validator
+	full_element: Cell<bindings::xmlNodePtr>,
+}
+
+impl Rng {
+	fn new(schema: &RelaxNgSchema) -> Result<Self, std::io::Error> {
+		let failed = || std::io::Error::new(ErrorKind::Other, "Failed to create the RELAX NG validation context");
+
+		let rng = Rng {
+			vctxt: unsafe { bindings::xmlRelaxNGNewValidCtxt(schema.as_ptr()) },
+			full_element: Cell::new(std::ptr::null_mut()),
+		};
+
+		if rng.vctxt.is_null() {
+			return Err(failed());
+		}
+
+		Ok(rng)
+	}
+
+	fn report_errors_to(&self, data_ptr: *mut c_void) {
+		unsafe { bindings::xmlRelaxNGSetValidStructuredErrors(self.vctxt, Some(sax_structured_error), data_ptr) };
+	}
+
+	// Called with the element the tree builder just started
+	fn push_element(&self, ctxt: xmlParserCtxtPtr) {
+		if !self.full_element.get().is_null() {
+			return;
+		}
+
+		unsafe {
+			let node = (*ctxt).node;
+			if bindings::xmlRelaxNGValidatePushElement(self.vctxt, (*ctxt).myDoc, node) == 0 {
+				self.full_element.set(node);
+			}
+		}
+	}
+
+	// The validator looks for the end of the text with a null byte
+	fn push_text(&self, chars: *const xmlChar, len: c_int) {
+		if self.full_element.get().is_null() {
+			let mut text = bytes_from_xmlchar(chars, len as isize).to_vec();
+			text.push(0);
+			unsafe { bindings::xmlRelaxNGValidatePushCData(self.vctxt, text.as_ptr(), len) };
+		}
+	}
+
+	// Returns whether the element is done with and can be freed
+	fn pop_element(&self, doc: bindings::xmlDocPtr, node: bindings::xmlNodePtr) -> bool {
+		unsafe {
+			if self.full_element.get().is_null() {
+				bindings::xmlRelaxNGValidatePopElement(self.vctxt, doc, node);
+				return true;
+			}
+
+			if self.full_element.get() == node {
+				bindings::xmlRelaxNGValidateFullElement(self.vctxt, doc, node);
+				self.full_element.set(std::ptr::null_mut());
+				return true;
+			}
+		}
+
+		false
+	}
+}
+
+impl Drop for Rng {
+	fn drop(&mut self) {
+		unsafe { bindings::xmlRelaxNGFreeValidCtxt(self.vctxt) };
+	}
+}
+
+// Decodes the document with the encoding given rather than the one it declares. The
+// bytes already pushed to the parser have not been decoded yet.
+fn switch_encoding(ctxt: xmlParserCtxtPtr, encoding: &str) -> Result<(), std::io::Error> {
+	let unknown = || std::io::Error::new(ErrorKind::InvalidInput, format!("Unknown encoding '{}'", encoding));
+
+	let name = CString::new(encoding).map_err(|_| unknown())?;
+	let encoding_handler = unsafe { bindings::xmlFindCharEncodingHandler(name.as_ptr()) };
+	if encoding_handler.is_null() || unsafe { bindings::xmlSwitchToEncoding(ctxt, encoding_handler) } != 0 {
+		return Err(unknown());
+	}
+
+	Ok(())
+}
+
+// Documents are untrusted unless `huge` is set. The network is never accessed and,
+// unless the document is validated, external DTDs are not loaded. Entities are not
+// expanded either since the handler does not record their declarations.
+//
+// Validated documents load their DTD and their entities are expanded within the
+// limits of libxml2. They are checked against the DTD they declare unless another one
+// is given.
+fn set_parser_options(ctxt: xmlParserCtxtPtr, format: InputFormat, opts: &ProgramOpts) {
+	let mut options = bindings::xmlParserOption_XML_PARSE_NONET;
+	if opts.encoding.is_some() {
+		options |= bindings::xmlParserOption_XML_PARSE_IGNORE_ENC;
+	}
+
+	if validates_dtd(format, opts) {
+		options |= bindings::xmlParserOption_XML_PARSE_DTDLOAD | bindings::xmlParserOption_XML_PARSE_NOENT;
+		if opts.dtd.is_none() {
+			options |= bindings::xmlParserOption_XML_PARSE_DTDVALID;
+		}
+	}
+
+	if opts.huge {
+		options |= bindings::xmlParserOption_XML_PARSE_HUGE;
+	}
+
+	// The HTML options have the same values as the XML ones
+	unsafe {
+		match format {
+			InputFormat::Xml => bindings::xmlCtxtUseOptions(ctxt, options as c_int),
+			InputFormat::Html => bindings::htmlCtxtUseOptions(ctxt, options as c_int),
+		};
+	}
+}
+
+fn free_parser_ctxt(ctxt: xmlParserCtxtPtr, format: InputFormat) {
+	unsafe {
+		match format {
+			InputFormat::Xml => bindings::xmlFreeParserCtxt(ctxt),
+			InputFormat::Html => bindings::htmlFreeParserCtxt(ctxt),
+		}
+	}
+}
 
-	let last = user_data.last_tag().unwrap();
-	if last.name() != name {
-		return
+fn read_chunk(reader: &mut dyn Read, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+	loop {
+		match reader.read(buf) {
+			Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+			result => return result,
+		}
+	}
+}
+
+extern fn sax_start_element_ns(
+	user_data_ptr: *mut c_void,
+	localname: *const xmlChar,
+	prefix: *const xmlChar,
+	uri: *const xmlChar,
+	nb_namespaces: c_int,
+	namespaces: *mut *const xmlChar,
+	nb_attributes: c_int,
+	nb_defaulted: c_int,
+	attributes: *mut *const xmlChar,
+) {
+	let context = deref_context(user_data_ptr);
+	context.start_element(nb_attributes as usize);
+	let name = context.decode_name(localname, prefix, uri);
+
+	// Pairs of prefix and URI
+	let declared: Vec<_> = slice_from_ptr(namespaces, nb_namespaces as usize * 2).chunks(2).map(|ns| {
+		(context.decode_option(ns[0]), context.decode_option(ns[1]))
+	}).collect();
+
+	// Tuples of localname, prefix, URI, value and end of the value. The values are not
+	// null terminated.
+	let attrs: Vec<_> = slice_from_ptr(attributes, nb_attributes as usize * 5).chunks(5).map(|attr| {
+		let len = unsafe { attr[4].offset_from(attr[3]) };
+		(context.decode_name(attr[0], attr[1], attr[2]), context.decode(bytes_from_xmlchar(attr[3], len)))
+	}).collect();
+
+	let declared: Vec<Namespace> = declared.iter().map(|(prefix, uri)| Namespace {
+		prefix: prefix.as_deref(),
+		uri: uri.as_deref().unwrap_or(""),
+	}).collect();
+	let attrs: Vec<Attribute> = attrs.iter().map(|(name, value)| Attribute { name: name.qname(), value }).collect();
+
+	if let Some(mut handler) = context.handler() {
+		handler.start_element(&name.qname(), &attrs, &declared);
+	}
+
+	// The errors about the element are found once the handler knows about it
+	if context.build_tree {
+		unsafe { bindings::xmlSAX2StartElementNs(context.sax2_ctxt(), localname, prefix, uri, nb_namespaces, namespaces, nb_attributes, nb_defaulted, attributes) };
+	}
+
+	if let Some(rng) = &context.rng {
+		rng.push_element(context.ctxt);
+	}
+
+	if let Some(start_element) = context.xsd.as_ref().and_then(|xsd| xsd.sax().startElementNs) {
+		let user_data = context.xsd.as_ref().unwrap().user_data();
+		unsafe { start_element(user_data, localname, prefix, uri, nb_namespaces, namespaces, nb_attributes, nb_defaulted, attributes) };
+	}
+}
+
+// The errors about the content of the element are found before the handler is told
+// that it ends
+extern fn sax_end_element_ns(user_data_ptr: *mut c_void, localname: *const xmlChar, prefix: *const xmlChar, uri: *const xmlChar) {
+	let context = deref_context(user_data_ptr);
+	if context.build_tree {
+		let node = unsafe { (*context.ctxt).node };
+		unsafe { bindings::xmlSAX2EndElementNs(context.sax2_ctxt(), localname, prefix, uri) };
+		context.end_tree_element(node);
+	}
+
+	if let Some(end_element) = context.xsd.as_ref().and_then(|xsd| xsd.sax().endElementNs) {
+		let user_data = context.xsd.as_ref().unwrap().user_data();
+		unsafe { end_element(user_data, localname, prefix, uri) };
+	}
+
+	context.end_element();
+	let name = context.decode_name(localname, prefix, uri);
+	if let Some(mut handler) = context.handler() {
+		handler.end_element(&name.qname());
+	}
+}
+
+// Only called by the HTML parser, the attributes are pairs of name and value in an
+// array terminated by a null name. Attributes without a value, such as
+// `<input disabled>`, have a null value.
+extern fn sax_start_element(user_data_ptr: *mut c_void, name: *const xmlChar, attributes: *mut *const xmlChar) {
+	let context = deref_context(user_data_ptr);
+	let name = context.decode_name(name, std::ptr::null(), std::ptr::null());
+
+	let mut len = 0;
+	if !attributes.is_null() {
+		while !unsafe { *attributes.add(len) }.is_null() {
+			len += 2;
+		}
+	}
+	context.start_element(len / 2);
+
+	let attrs: Vec<_> = slice_from_ptr(attributes, len).chunks(2).map(|attr| {
+		(context.decode_name(attr[0], std::ptr::null(), std::ptr::null()), context.decode_option(attr[1]))
+	}).collect();
+	let attrs: Vec<Attribute> = attrs.iter().map(|(name, value)| Attribute {
+		name: name.qname(),
+		value: value.as_deref().unwrap_or(""),
+	}).collect();
+
+	if let Some(mut handler) = context.handler() {
+		handler.start_element(&name.qname(), &attrs, &[]);
 	}
+}
 
-	user_data.print_last_tag();
-	user_data.pop_tag();
+extern fn sax_end_element(user_data_ptr: *mut c_void, name: *const xmlChar) {
+	let context = deref_context(user_data_ptr);
+	context.end_element();
+	let name = context.decode_name(name, std::ptr::null(), std::ptr::null());
+	if let Some(mut handler) = context.handler() {
+		handler.end_element(&name.qname());
+	}
 }
 
 extern fn sax_characters(user_data_ptr: *mut c_void, chars: *const xmlChar, len: i32) {
-	let user_data = deref_mut_void_ptr::<ParserData>(user_data_ptr);
-	let chars = str_from_xmlchar(chars, len as isize);
-	if !user_data.opts().keep_all_whitespace && is_only_whitespace(&chars) {
-		return;
+	let context = deref_context(user_data_ptr);
+	if context.build_tree {
+		unsafe { bindings::xmlSAX2Characters(context.sax2_ctxt(), chars, len) };
 	}
 
-	let (opts, tags, write_buf) = user_data.opts_tags_and_buf_mut();
-	write!(write_buf, "{}=\"", tags).unwrap();
-	print_string(write_buf, chars, opts).unwrap();
-	writeln!(write_buf, "\"").unwrap();
+	context.validate_text(chars, len, false);
 
-	user_data.last_tag_mut().unwrap().set_printed(true);
+	context.text(len as usize);
+	let chars = context.decode(bytes_from_xmlchar(chars, len as isize));
+	if let Some(mut handler) = context.handler() {
+		handler.characters(&chars);
+	}
 }
 
-extern fn sax_processing_instruction(user_data_ptr: *mut c_void, target: *const xmlChar, data: *const xmlChar) {
-	let user_data = deref_mut_void_ptr::<ParserData>(user_data_ptr);
-	let target = str_from_xmlchar_with_null(target);
-	let data = str_from_xmlchar_with_null(data);
+extern fn sax_cdata_block(user_data_ptr: *mut c_void, data: *const xmlChar, len: c_int) {
+	let context = deref_context(user_data_ptr);
+	if context.build_tree {
+		unsafe { bindings::xmlSAX2CDataBlock(context.sax2_ctxt(), data, len) };
+	}
+
+	context.validate_text(data, len, true);
 
-	let (opts, tags, write_buf) = user_data.opts_tags_and_buf_mut();
-	write!(write_buf, "{}/{}?[", tags, target).unwrap();
-	print_string(write_buf, data, opts).unwrap();
-	writeln!(write_buf, "]").unwrap();
+	context.text(len as usize);
+	let data = context.decode(bytes_from_xmlchar(data, len as isize));
+	if let Some(mut handler) = context.handler() {
+		handler.cdata(&data);
+	}
+}
+
+extern fn sax_processing_instruction(user_data_ptr: *mut c_void, target: *const xmlChar, data: *const xmlChar) {
+	let context = deref_context(user_data_ptr);
+	context.text_size.set(0);
+	let target = context.decode(bytes_from_xmlchar_with_null(target));
+	let data = context.decode_option(data);
+	if let Some(mut handler) = context.handler() {
+		handler.processing_instruction(&target, data.as_deref().unwrap_or(""));
+	}
 }
 
 extern fn sax_comment(user_data_ptr: *mut c_void, comment: *const xmlChar) {
-	let user_data = deref_mut_void_ptr::<ParserData>(user_data_ptr);
-	let comment = str_from_xmlchar_with_null(comment);
+	let context = deref_context(user_data_ptr);
+	context.text_size.set(0);
+	let comment = context.decode(bytes_from_xmlchar_with_null(comment));
+	if let Some(mut handler) = context.handler() {
+		handler.comment(&comment);
+	}
+}
 
-	let (opts, tags, write_buf) = user_data.opts_tags_and_buf_mut();
-	write!(write_buf, "{}/![", tags).unwrap();
-	print_string(write_buf, comment, opts).unwrap();
-	writeln!(write_buf, "]").unwrap();
+// The SAX2 callbacks of libxml2 expect the parser context as their user data
+macro_rules! sax2_callback {
+	($name:ident => $sax2:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)?) => {
+		extern fn $name(user_data_ptr: *mut c_void, $($arg: $ty),*) $(-> $ret)? {
+			let context = deref_context(user_data_ptr);
+			unsafe { bindings::$sax2(context.sax2_ctxt(), $($arg),*) }
+		}
+	};
 }
 
-#[inline(always)]
-fn deref_mut_void_ptr<'a, T>(ptr: *mut c_void) -> &'a mut T {
-	let ptr = ptr as *mut T;
-	unsafe { &mut *ptr }
+sax2_callback!(sax_internal_subset => xmlSAX2InternalSubset(name: *const xmlChar, external_id: *const xmlChar, system_id: *const xmlChar));
+sax2_callback!(sax_external_subset => xmlSAX2ExternalSubset(name: *const xmlChar, external_id: *const xmlChar, system_id: *const xmlChar));
+sax2_callback!(sax_resolve_entity => xmlSAX2ResolveEntity(public_id: *const xmlChar, system_id: *const xmlChar) -> bindings::xmlParserInputPtr);
+sax2_callback!(sax_is_standalone => xmlSAX2IsStandalone() -> c_int);
+sax2_callback!(sax_has_internal_subset => xmlSAX2HasInternalSubset() -> c_int);
+sax2_callback!(sax_has_external_subset => xmlSAX2HasExternalSubset() -> c_int);
+sax2_callback!(sax_get_entity => xmlSAX2GetEntity(name: *const xmlChar) -> bindings::xmlEntityPtr);
+sax2_callback!(sax_get_parameter_entity => xmlSAX2GetParameterEntity(name: *const xmlChar) -> bindings::xmlEntityPtr);
+sax2_callback!(sax_notation_decl => xmlSAX2NotationDecl(name: *const xmlChar, public_id: *const xmlChar, system_id: *const xmlChar));
+sax2_callback!(sax_attribute_decl => xmlSAX2AttributeDecl(elem: *const xmlChar, fullname: *const xmlChar, type_: c_int, def: c_int, default_value: *const xmlChar, tree: bindings::xmlEnumerationPtr));
+sax2_callback!(sax_element_decl => xmlSAX2ElementDecl(name: *const xmlChar, type_: c_int, content: bindings::xmlElementContentPtr));
+sax2_callback!(sax_unparsed_entity_decl => xmlSAX2UnparsedEntityDecl(name: *const xmlChar, public_id: *const xmlChar, system_id: *const xmlChar, notation_name: *const xmlChar));
+sax2_callback!(sax_start_document => xmlSAX2StartDocument());
+sax2_callback!(sax_end_document => xmlSAX2EndDocument());
+
+// External entities, general or parameter ones, are not declared so that the files
+// they refer to are not read, referencing them is an error
+extern fn sax_entity_decl(user_data_ptr: *mut c_void, name: *const xmlChar, type_: c_int, public_id: *const xmlChar, system_id: *const xmlChar, content: *mut xmlChar) {
+	let context = deref_context(user_data_ptr);
+	match type_ as u32 {
+		bindings::xmlEntityType_XML_EXTERNAL_GENERAL_PARSED_ENTITY | bindings::xmlEntityType_XML_EXTERNAL_PARAMETER_ENTITY => return,
+		_ => (),
+	}
+
+	unsafe { bindings::xmlSAX2EntityDecl(context.sax2_ctxt(), name, type_, public_id, system_id, content) };
 }
 
-#[inline(always)]
-fn is_only_whitespace(string: &str) -> bool {
-	string.trim().is_empty()
+// Called by the error callbacks in `sax_funcs.c` with the formatted message
+extern fn sax_report(user_data_ptr: *mut c_void, severity: bindings::sax_severity, msg: *const c_char) {
+	let context = deref_context(user_data_ptr);
+	let severity = match severity {
+		bindings::sax_severity_SAX_WARNING => Severity::Warning,
+		bindings::sax_severity_SAX_ERROR => Severity::Error,
+		_ => Severity::Fatal,
+	};
+
+	let message = unsafe { CStr::from_ptr(msg) }.to_string_lossy();
+	context.diagnostics.borrow_mut().push(Diagnostic { severity, message: message.trim_end().to_owned(), line: None, column: None });
+}
+
+// Receives the errors of the parser in place of the callbacks in `sax_funcs.c`
+extern fn sax_structured_error(user_data_ptr: *mut c_void, error: bindings::xmlErrorPtr) {
+	let error = unsafe { &*error };
+
+	// Validity errors are reported with the parser context as their user data
+	let context = if !error.ctxt.is_null() && user_data_ptr == error.ctxt {
+		deref_context(unsafe { (*(error.ctxt as xmlParserCtxtPtr))._private })
+	} else {
+		deref_context(user_data_ptr)
+	};
+	let severity = match error.level {
+		bindings::xmlErrorLevel_XML_ERR_WARNING => Severity::Warning,
+		bindings::xmlErrorLevel_XML_ERR_ERROR => Severity::Error,
+		_ => Severity::Fatal,
+	};
+
+	let message = if error.message.is_null() {
+		String::from("Unknown error")
+	} else {
+		unsafe { CStr::from_ptr(error.message) }.to_string_lossy().trim_end().to_owned()
+	};
+
+	// The validity errors found while parsing are about the current element
+	let validity = matches!(error.domain as u32,
+		bindings::xmlErrorDomain_XML_FROM_VALID | bindings::xmlErrorDomain_XML_FROM_SCHEMASV | bindings::xmlErrorDomain_XML_FROM_RELAXNGV);
+	if validity && severity == Severity::Error && !context.parsed.get() {
+		if let Some(mut handler) = context.handler() {
+			handler.invalid(&message);
+		}
+	}
+
+	context.diagnostics.borrow_mut().push(Diagnostic {
+		severity,
+		message,
+		line: positive(error.line),
+		column: positive(error.int2),
+	});
+}
+
+// Locates the errors of the XML schema validator at the position of the parser
+extern fn xsd_locator(user_data_ptr: *mut c_void, file: *mut *const c_char, line: *mut c_ulong) -> c_int {
+	let context = deref_context(user_data_ptr);
+	if context.ctxt.is_null() {
+		return -1;
+	}
+
+	unsafe {
+		*file = std::ptr::null();
+		*line = Locator::new(context.ctxt).line() as c_ulong;
+	}
+
+	0
+}
+
+unsafe fn free_node(node: bindings::xmlNodePtr) {
+	bindings::xmlUnlinkNode(node);
+	bindings::xmlFreeNode(node);
 }
 
 #[inline(always)]
-pub fn print_string<W: Write>(write_buf: &mut W, string: &str, opts: &ProgramOpts) -> Result<(), std::io::Error> {
-	if !opts.map_whitespace && !opts.compress_whitespace {
-		return write_buf.write_all(string.as_bytes());
+fn positive(n: c_int) -> Option<u32> {
+	if n > 0 { Some(n as u32) } else { None }
+}
+
+impl<'a> SaxContext<'a> {
+	// Tells the handler where the event it is about to receive is located. `None` once
+	// the parser is stopped.
+	fn handler(&self) -> Option<RefMut<'_, &'a mut dyn XmlEventHandler>> {
+		if self.stopped.get() {
+			return None;
+		}
+
+		let mut handler = self.handler.borrow_mut();
+		if !self.ctxt.is_null() {
+			handler.location(&Locator::new(self.ctxt));
+		}
+
+		Some(handler)
 	}
 
-	// Shared buffer to translate a char to byte slice
-	let mut buf = [0; 4];
+	// Strings that are not valid UTF-8 are converted according to `invalid_utf8`. With
+	// `InvalidUtf8::Fail` the parser is stopped with a fatal error and the invalid
+	// sequences are replaced in the meantime.
+	fn decode<'s>(&self, bytes: &'s [u8]) -> Cow<'s, str> {
+		let error = match std::str::from_utf8(bytes) {
+			Ok(string) => return Cow::Borrowed(string),
+			Err(e) => e,
+		};
 
-	// Map whitespace without compressing
-	if !opts.compress_whitespace {
-		for char in string.chars() {
-			transliterate_and_print(write_buf, char, opts.space_map, opts.tab_map, opts.newline_map, &mut buf)?;
+		match self.invalid_utf8 {
+			InvalidUtf8::Replace => String::from_utf8_lossy(bytes),
+			InvalidUtf8::Escape => Cow::Owned(escape_invalid_utf8(bytes)),
+			InvalidUtf8::Fail => {
+				if !self.stopped.get() {
+					self.stop(format!("Invalid UTF-8 sequence starting with byte 0x{:02X}", bytes[error.valid_up_to()]));
+				}
+
+				String::from_utf8_lossy(bytes)
+			},
 		}
+	}
 
-		return Ok(());
+	#[inline(always)]
+	fn decode_option<'s>(&self, chars: *const xmlChar) -> Option<Cow<'s, str>> {
+		option_bytes_from_xmlchar_with_null(chars).map(|bytes| self.decode(bytes))
 	}
 
-	// NOTE: The most efficient way for transliterating whitespace characters to UTF-8
-	//       characters is to do it right before printing the character. Doing this is
-	//       much cheaper than allocating a new buffer and transliterating into it.
-	//       Another approach I thought of is to modify the string right before storing
-	//       it into memory, but it would require modifying the libxml2 library and that
-	//       is too much of a hassle.
-	let (space_char, tab_char, newline_char) = if opts.map_whitespace {
-		(opts.space_map, opts.tab_map, opts.newline_map)
-	} else {
-		(' ', '\t', '\n')
-	};
+	#[inline(always)]
+	fn decode_name<'s>(&self, localname: *const xmlChar, prefix: *const xmlChar, uri: *const xmlChar) -> Name<'s> {
+		Name {
+			local_name: self.decode(bytes_from_xmlchar_with_null(localname)),
+			prefix: self.decode_option(prefix),
+			uri: self.decode_option(uri),
+		}
+	}
+
+	// Stops the parser if the element exceeds the limits on nesting or attributes
+	fn start_element(&self, attributes: usize) {
+		self.depth.set(self.depth.get() + 1);
+		self.text_size.set(0);
+		if self.stopped.get() {
+			return;
+		}
 
-	let mut space_count = 0;
-	for char in string.chars() {
-		if char != ' ' {
-			if space_count < opts.compress_level {
-			    for _ in 0..space_count {
-			        write_buf.write(char_to_bytes(space_char, &mut buf))?;
-			    }
-			} else {
-				write_buf.write(char_to_bytes(tab_char, &mut buf))?;
+		if let Some(max) = self.limits.max_depth.filter(|&max| self.depth.get() > max) {
+			self.stop(format!("Elements are nested more than {} levels deep", max));
+		} else if let Some(max) = self.limits.max_attributes.filter(|&max| attributes > max) {
+			self.stop(format!("An element has more than {} attributes", max));
+		}
+	}
+
+	fn end_element(&self) {
+		self.depth.set(self.depth.get().saturating_sub(1));
+		self.text_size.set(0);
+	}
+
+	// Text reported by several callbacks in a row is counted as a single node
+	fn text(&self, len: usize) {
+		self.text_size.set(self.text_size.get() + len);
+		if self.stopped.get() {
+			return;
+		}
+
+		if let Some(max) = self.limits.max_text_size.filter(|&max| self.text_size.get() > max) {
+			self.stop(format!("A text node is longer than {} bytes", max));
+		}
+	}
+
+	// Called with the element the tree builder just ended. It is freed once the RELAX NG
+	// schema is done with it unless the whole tree is kept.
+	fn end_tree_element(&self, node: bindings::xmlNodePtr) {
+		let rng = match &self.rng {
+			Some(rng) => rng,
+			None => return,
+		};
+
+		let doc = unsafe { (*self.ctxt).myDoc };
+		if !rng.pop_element(doc, node) || self.keep_tree {
+			return;
+		}
+
+		// The text before the element is freed with it, since libxml2 would append the
+		// next text to it as if it were the text it just added
+		let parent = unsafe { (*self.ctxt).node };
+		unsafe {
+			if parent.is_null() {
+				free_node(node);
+			}
+
+			while !parent.is_null() {
+				match bindings::xmlGetLastChild(parent) {
+					child if child.is_null() => break,
+					child => free_node(child),
+				}
 			}
-			space_count = 0;
+		}
+	}
 
-			transliterate_and_print(write_buf, char, space_char, tab_char, newline_char, &mut buf)?;
-			continue;
+	// Passes text and CDATA sections on to the schema validators
+	fn validate_text(&self, chars: *const xmlChar, len: c_int, cdata: bool) {
+		if let Some(rng) = &self.rng {
+			rng.push_text(chars, len);
 		}
 
-		space_count += 1;
-		if space_count == opts.compress_level {
-			write_buf.write(char_to_bytes(tab_char, &mut buf))?;
-			space_count = 0;
+		let xsd = match &self.xsd {
+			Some(xsd) => xsd,
+			None => return,
+		};
+
+		let callback = if cdata { xsd.sax().cdataBlock } else { xsd.sax().characters };
+		if let Some(callback) = callback {
+			unsafe { callback(xsd.user_data(), chars, len) };
 		}
 	}
 
-	// Print any spaces that weren't printed
-	for _ in 0..space_count {
-	    write_buf.write(char_to_bytes(space_char, &mut buf))?;
+	#[inline(always)]
+	fn sax2_ctxt(&self) -> *mut c_void {
+		self.ctxt as *mut c_void
 	}
 
-	Ok(())
+	fn stop(&self, message: String) {
+		let locator = Locator::new(self.ctxt);
+		self.diagnostics.borrow_mut().push(Diagnostic {
+			severity: Severity::Fatal,
+			message,
+			line: Some(locator.line()),
+			column: Some(locator.column()),
+		});
+
+		self.stopped.set(true);
+		unsafe { bindings::xmlStopParser(self.ctxt) };
+	}
 }
 
-#[inline(always)]
-fn transliterate_and_print<W: Write>(write_buf: &mut W, c: char, space: char, tab: char, newline: char, buf: &mut [u8]) -> Result<(), std::io::Error> {
-	write_buf.write(char_to_bytes(match c {
-		' '  =>  space,
-		'\t' =>  tab,
-		'\n' =>  newline,
-		   _ =>  c,
-	}, buf))?;
+impl<'a> Name<'a> {
+	fn qname(&self) -> QName<'_> {
+		QName {
+			local_name: &self.local_name,
+			prefix: self.prefix.as_deref(),
+			uri: self.uri.as_deref(),
+		}
+	}
+}
 
-	Ok(())
+// Replaces every byte of the invalid sequences with `\xNN`
+fn escape_invalid_utf8(mut bytes: &[u8]) -> String {
+	let mut escaped = String::with_capacity(bytes.len() + 16);
+	loop {
+		match std::str::from_utf8(bytes) {
+			Ok(valid) => {
+				escaped.push_str(valid);
+				return escaped;
+			},
+			Err(e) => {
+				let (valid, rest) = bytes.split_at(e.valid_up_to());
+				escaped.push_str(std::str::from_utf8(valid).unwrap_or_default());
+
+				// A sequence cut short at the end of the string has no length
+				let len = e.error_len().unwrap_or(rest.len());
+				rest[..len].iter().for_each(|b| write!(escaped, "\\x{:02X}", b).unwrap());
+				bytes = &rest[len..];
+			},
+		}
+	}
 }
 
 #[inline(always)]
-fn char_to_bytes(c: char, buf: &mut [u8]) -> &[u8] {
-	c.encode_utf8(buf).as_bytes()
+fn deref_context<'a, 'h>(ptr: *mut c_void) -> &'a SaxContext<'h> {
+	unsafe { &*(ptr as *const SaxContext) }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::escape_invalid_utf8;
+
+	#[test]
+	fn valid_unchanged() {
+		assert_eq!(escape_invalid_utf8(b""), "");
+		assert_eq!(escape_invalid_utf8("a\\xé€😀".as_bytes()), "a\\xé€😀");
+	}
+
+	#[test]
+	fn invalid_bytes_escaped() {
+		assert_eq!(escape_invalid_utf8(b"a\xffb"), "a\\xFFb");
+		assert_eq!(escape_invalid_utf8(b"\x80\x80"), "\\x80\\x80");
+		assert_eq!(escape_invalid_utf8(b"\xc0\xaf"), "\\xC0\\xAF");
+		assert_eq!(escape_invalid_utf8(b"\xed\xa0\x80"), "\\xED\\xA0\\x80");
+	}
+
+	#[test]
+	fn invalid_sequence_followed_by_valid() {
+		assert_eq!(escape_invalid_utf8(b"\xe2\x28\xa1"), "\\xE2(\\xA1");
+		assert_eq!(escape_invalid_utf8(b"\xe2\x82\xe2\x82\xac"), "\\xE2\\x82€");
+	}
+
+	#[test]
+	fn truncated_sequence_at_end() {
+		assert_eq!(escape_invalid_utf8(b"a\xe2\x82"), "a\\xE2\\x82");
+		assert_eq!(escape_invalid_utf8(b"\xf0\x9f\x98"), "\\xF0\\x9F\\x98");
+		assert_eq!(escape_invalid_utf8(b"\xc3"), "\\xC3");
+	}
 }
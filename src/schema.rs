@@ -0,0 +1,156 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bindings::{ self, xmlChar };
+
+use std::ffi::CString;
+use std::io::ErrorKind;
+use std::sync::{ Mutex, MutexGuard };
+
+// The schemas are loaded once and shared by the documents parsed on every thread, each
+// document is checked with a validation context of its own. libxml2 only reads the
+// compiled XML and RELAX NG schemas while validating.
+
+/// A DTD to validate XML documents against rather than the one they declare.
+pub struct Dtd {
+	dtd: bindings::xmlDtdPtr,
+	// libxml2 builds the content models of the elements the first time it checks them
+	lock: Mutex<()>,
+}
+
+/// A compiled XML schema.
+pub struct XmlSchema(bindings::xmlSchemaPtr);
+
+/// A compiled RELAX NG schema.
+pub struct RelaxNgSchema(bindings::xmlRelaxNGPtr);
+
+unsafe impl Send for Dtd {}
+unsafe impl Sync for Dtd {}
+unsafe impl Send for XmlSchema {}
+unsafe impl Sync for XmlSchema {}
+unsafe impl Send for RelaxNgSchema {}
+unsafe impl Sync for RelaxNgSchema {}
+
+impl Dtd {
+	pub fn load(path: &str) -> Result<Self, std::io::Error> {
+		let failed = || std::io::Error::new(ErrorKind::InvalidInput, format!("Failed to load the DTD '{}'", path));
+
+		let path = CString::new(path).map_err(|_| failed())?;
+		let dtd = without_network(|| unsafe { bindings::xmlParseDTD(std::ptr::null(), path.as_ptr() as *const xmlChar) });
+		if dtd.is_null() {
+			return Err(failed());
+		}
+
+		Ok(Dtd { dtd, lock: Mutex::new(()) })
+	}
+
+	// Only one document is checked against the DTD at a time, until the guard is dropped
+	pub(crate) fn lock(&self) -> (bindings::xmlDtdPtr, MutexGuard<'_, ()>) {
+		let guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+		(self.dtd, guard)
+	}
+}
+
+impl Drop for Dtd {
+	fn drop(&mut self) {
+		unsafe { bindings::xmlFreeDtd(self.dtd) };
+	}
+}
+
+impl XmlSchema {
+	pub fn load(path: &str) -> Result<Self, std::io::Error> {
+		let failed = || std::io::Error::new(ErrorKind::InvalidInput, format!("Failed to load the XML schema '{}'", path));
+
+		let path = CString::new(path).map_err(|_| failed())?;
+		let schema = without_network(|| unsafe {
+			let parser_ctxt = bindings::xmlSchemaNewParserCtxt(path.as_ptr());
+			if parser_ctxt.is_null() {
+				return std::ptr::null_mut();
+			}
+
+			let schema = bindings::xmlSchemaParse(parser_ctxt);
+			bindings::xmlSchemaFreeParserCtxt(parser_ctxt);
+			schema
+		});
+
+		if schema.is_null() {
+			return Err(failed());
+		}
+
+		Ok(XmlSchema(schema))
+	}
+
+	#[inline(always)]
+	pub(crate) fn as_ptr(&self) -> bindings::xmlSchemaPtr {
+		self.0
+	}
+}
+
+impl Drop for XmlSchema {
+	fn drop(&mut self) {
+		unsafe { bindings::xmlSchemaFree(self.0) };
+	}
+}
+
+impl RelaxNgSchema {
+	pub fn load(path: &str) -> Result<Self, std::io::Error> {
+		let failed = || std::io::Error::new(ErrorKind::InvalidInput, format!("Failed to load the RELAX NG schema '{}'", path));
+
+		let path = CString::new(path).map_err(|_| failed())?;
+		let schema = without_network(|| unsafe {
+			let parser_ctxt = bindings::xmlRelaxNGNewParserCtxt(path.as_ptr());
+			if parser_ctxt.is_null() {
+				return std::ptr::null_mut();
+			}
+
+			let schema = bindings::xmlRelaxNGParse(parser_ctxt);
+			bindings::xmlRelaxNGFreeParserCtxt(parser_ctxt);
+			schema
+		});
+
+		if schema.is_null() {
+			return Err(failed());
+		}
+
+		Ok(RelaxNgSchema(schema))
+	}
+
+	#[inline(always)]
+	pub(crate) fn as_ptr(&self) -> bindings::xmlRelaxNGPtr {
+		self.0
+	}
+}
+
+impl Drop for RelaxNgSchema {
+	fn drop(&mut self) {
+		unsafe { bindings::xmlRelaxNGFree(self.0) };
+	}
+}
+
+// The DTD and schema parsers can't be given the parser options, the entity loader of
+// libxml2 that refuses the network is installed while they load the files they include.
+// The loader is global, the schemas are loaded before the documents are parsed.
+fn without_network<T>(load: impl FnOnce() -> T) -> T {
+	unsafe {
+		let loader = bindings::xmlGetExternalEntityLoader();
+		bindings::xmlSetExternalEntityLoader(Some(bindings::xmlNoNetExternalEntityLoader));
+		let result = load();
+		bindings::xmlSetExternalEntityLoader(loader);
+		result
+	}
+}
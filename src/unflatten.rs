@@ -0,0 +1,649 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::config::{ EscapeMode, ProgramOpts };
+
+use crate::unmap::unmap_string;
+
+use std::borrow::Cow;
+use std::io::{ BufRead, Write };
+use std::io::{ Error, ErrorKind };
+
+enum Line<'a> {
+	Element(Vec<&'a str>),
+	Attributes(Vec<&'a str>, &'a str),
+	Text(Vec<&'a str>, &'a str),
+	CData(Vec<&'a str>, &'a str),
+	Comment(Vec<&'a str>, &'a str),
+	ProcessingInstruction(Vec<&'a str>, &'a str, &'a str),
+	Namespace(Vec<&'a str>, &'a str, &'a str),
+}
+
+enum LineKind {
+	Element,
+	Attributes,
+	Text,
+	CData,
+	Comment,
+	ProcessingInstruction,
+	Namespace,
+}
+
+// The position of the different parts of a line produced by `sax.rs`
+struct LineHeader {
+	kind: LineKind,
+	path_end: usize,
+	value_start: usize,
+}
+
+struct XmlWriter<'w, W: Write> {
+	out: &'w mut W,
+	tags: Vec<String>,
+	start_tag_open: bool,
+}
+
+pub fn unflatten<R: BufRead, W: Write>(input: R, output: &mut W, opts: &ProgramOpts) -> Result<(), Error> {
+	let mut writer = XmlWriter::new(output);
+	writer.write_declaration()?;
+
+	let mut lines = input.lines();
+	while let Some(line) = lines.next() {
+		let mut line = line?;
+		line.drain(..location_len(&line));
+
+		let header = match parse_header(&line) {
+			Some(header) => header,
+			None => return Err(invalid_line(&line)),
+		};
+
+		// Values containing newlines span multiple lines, keep reading until the
+		// value's terminating character is found
+		if let Some(terminator) = header.kind.terminator() {
+			while !is_terminated(&line, header.value_start, terminator) {
+				match lines.next() {
+					Some(next) => {
+						line.push('\n');
+						line.push_str(&next?);
+					},
+					None => return Err(invalid_line(&line)),
+				}
+			}
+		}
+
+		match parse_line(&line, header) {
+			Some(parsed) => writer.write_line(parsed, opts)?,
+			None => return Err(invalid_line(&line)),
+		}
+	}
+
+	writer.finish()
+}
+
+impl LineKind {
+	fn terminator(&self) -> Option<&'static str> {
+		match self {
+			LineKind::Element => None,
+			LineKind::Text => Some("\""),
+			LineKind::CData => Some("]]"),
+			LineKind::Attributes | LineKind::Comment | LineKind::ProcessingInstruction | LineKind::Namespace => Some("]"),
+		}
+	}
+}
+
+// The length of the `line:column:` and byte offset annotations at the start of a line
+fn location_len(line: &str) -> usize {
+	let end = line.find(|c: char| !c.is_ascii_digit() && c != ':').unwrap_or(line.len());
+	line[..end].rfind(':').map_or(0, |pos| pos + 1)
+}
+
+fn parse_header(line: &str) -> Option<LineHeader> {
+	if !line.is_empty() && !line.starts_with('/') {
+		return None;
+	}
+
+	// None of these characters are allowed in element names, so the first one
+	// found marks the end of the path
+	let pos = match line.find(&['@', '=', '!', '?'][..]) {
+		Some(pos) => pos,
+		None if line.is_empty() => return None,
+		None => return Some(LineHeader { kind: LineKind::Element, path_end: line.len(), value_start: line.len() }),
+	};
+
+	let rest = &line[pos..];
+	let (kind, path_end) = if rest.starts_with("@[") {
+		(LineKind::Attributes, pos)
+	} else if rest.starts_with("=\"") {
+		(LineKind::Text, pos)
+	} else if rest.starts_with("![CDATA[") && line[..pos].ends_with('/') {
+		return Some(LineHeader { kind: LineKind::CData, path_end: pos - 1, value_start: pos + 8 });
+	} else if rest.starts_with("![") && line[..pos].ends_with('/') {
+		(LineKind::Comment, pos - 1)
+	} else if rest.starts_with("?[") {
+		(LineKind::ProcessingInstruction, line[..pos].rfind('/')?)
+	} else if rest.starts_with("=[") && is_xmlns(&line[..pos]) {
+		(LineKind::Namespace, line[..pos].rfind('/')?)
+	} else {
+		return None;
+	};
+
+	Some(LineHeader { kind, path_end, value_start: pos + 2 })
+}
+
+fn parse_line(line: &str, header: LineHeader) -> Option<Line<'_>> {
+	// xmlparse never prints empty names, like the ones of `//a` or `/@[a=1]`
+	let path = split_path(&line[..header.path_end]);
+	if path.iter().any(|name| strip_index(name).is_empty()) {
+		return None;
+	}
+
+	let value = match header.kind.terminator() {
+		None => "",
+		Some(terminator) => &line[header.value_start..line.len() - terminator.len()],
+	};
+
+	Some(match header.kind {
+		LineKind::Element => Line::Element(path),
+		LineKind::Attributes if !path.is_empty() => Line::Attributes(path, value),
+		LineKind::Text if !path.is_empty() => Line::Text(path, value),
+		LineKind::CData if !path.is_empty() => Line::CData(path, value),
+		LineKind::Comment => Line::Comment(path, value),
+		LineKind::ProcessingInstruction => {
+			let target = &line[header.path_end + 1..header.value_start - 2];
+			Line::ProcessingInstruction(path, target, value)
+		},
+		LineKind::Namespace if !path.is_empty() => {
+			let name = &line[header.path_end + 1..header.value_start - 2];
+			Line::Namespace(path, name, value)
+		},
+		_ => return None,
+	})
+}
+
+#[inline(always)]
+fn split_path(path: &str) -> Vec<&str> {
+	path.split('/').skip(1).collect()
+}
+
+// Removes the sibling index printed with `--indices` from a path segment
+#[inline(always)]
+fn strip_index(name: &str) -> &str {
+	match name.find('[') {
+		Some(pos) if name.ends_with(']') => &name[..pos],
+		_ => name,
+	}
+}
+
+// Whether the last element of the path is an `xmlns` or `xmlns:prefix` declaration
+#[inline(always)]
+fn is_xmlns(path: &str) -> bool {
+	let name = path.rsplit('/').next().unwrap_or("");
+	name == "xmlns" || name.starts_with("xmlns:")
+}
+
+#[inline(always)]
+fn is_terminated(line: &str, value_start: usize, terminator: &str) -> bool {
+	line.len() >= value_start + terminator.len() && line.ends_with(terminator)
+}
+
+fn invalid_line(line: &str) -> Error {
+	Error::new(ErrorKind::InvalidData, format!("Invalid line: {}", line))
+}
+
+// Splits `name=value,name=value` pairs. Unless they are escaped, values are printed
+// raw so they may contain commas themselves; a comma only separates two attributes
+// if it is followed by something that looks like `name=`.
+fn split_attributes<'a>(attrs: &'a str, opts: &ProgramOpts) -> Vec<(&'a str, &'a str)> {
+	let mut pairs = Vec::new();
+
+	let mut rest = attrs;
+	while let Some(eq) = rest.find('=') {
+		let name = &rest[..eq];
+		let value = &rest[eq + 1..];
+
+		let end = match opts.escape_mode {
+			EscapeMode::None => value.match_indices(',')
+				.map(|(i, _)| i)
+				.find(|&i| starts_with_attribute_name(&value[i + 1..])),
+			EscapeMode::C => find_unescaped_comma(value),
+			EscapeMode::Xml => value.find(','),
+		}.unwrap_or(value.len());
+
+		pairs.push((name, &value[..end]));
+		rest = if end == value.len() { "" } else { &value[end + 1..] };
+	}
+
+	pairs
+}
+
+fn starts_with_attribute_name(string: &str) -> bool {
+	match string.find('=') {
+		Some(0) | None => false,
+		Some(eq) => !string[..eq].contains(|c: char| c == ',' || c == '"' || c.is_whitespace()),
+	}
+}
+
+fn find_unescaped_comma(string: &str) -> Option<usize> {
+	let mut escaped = false;
+	for (i, c) in string.char_indices() {
+		match c {
+			_ if escaped => escaped = false,
+			'\\' => escaped = true,
+			',' => return Some(i),
+			_ => {},
+		}
+	}
+
+	None
+}
+
+// Whitespace mapping is applied to the characters that are not escaped, so it is
+// reversed first
+fn decode_value<'a>(string: &'a str, opts: &ProgramOpts) -> Cow<'a, str> {
+	match unmap_string(string, opts) {
+		Cow::Borrowed(string) => unescape(string, opts),
+		Cow::Owned(string) => Cow::Owned(unescape(&string, opts).into_owned()),
+	}
+}
+
+fn unescape<'a>(string: &'a str, opts: &ProgramOpts) -> Cow<'a, str> {
+	match opts.escape_mode {
+		EscapeMode::None => Cow::Borrowed(string),
+		EscapeMode::C => unescape_c(string),
+		EscapeMode::Xml => unescape_xml(string),
+	}
+}
+
+fn unescape_c(string: &str) -> Cow<'_, str> {
+	if !string.contains('\\') {
+		return Cow::Borrowed(string);
+	}
+
+	let mut unescaped = String::with_capacity(string.len());
+	let mut chars = string.chars();
+	while let Some(char) = chars.next() {
+		if char != '\\' {
+			unescaped.push(char);
+			continue;
+		}
+
+		match chars.next() {
+			Some('n') => unescaped.push('\n'),
+			Some('t') => unescaped.push('\t'),
+			Some('r') => unescaped.push('\r'),
+			Some('u') => {
+				let rest = chars.as_str();
+				let code = rest.strip_prefix('{')
+					.and_then(|rest| rest.find('}').map(|end| &rest[..end]))
+					.and_then(|hex| u32::from_str_radix(hex, 16).ok().map(|code| (code, hex.len())))
+					.and_then(|(code, len)| std::char::from_u32(code).map(|c| (c, len)));
+
+				match code {
+					Some((c, len)) => {
+						unescaped.push(c);
+						chars = rest[len + 2..].chars();
+					},
+					None => unescaped.push('u'),
+				}
+			},
+			Some(c) => unescaped.push(c),
+			None => unescaped.push('\\'),
+		}
+	}
+
+	Cow::Owned(unescaped)
+}
+
+fn unescape_xml(string: &str) -> Cow<'_, str> {
+	if !string.contains('&') {
+		return Cow::Borrowed(string);
+	}
+
+	let mut unescaped = String::with_capacity(string.len());
+	let mut rest = string;
+	while let Some(amp) = rest.find('&') {
+		unescaped.push_str(&rest[..amp]);
+		rest = &rest[amp..];
+
+		let entity = rest.find(';').and_then(|semi| decode_entity(&rest[1..semi]).map(|c| (c, semi)));
+		match entity {
+			Some((c, semi)) => {
+				unescaped.push(c);
+				rest = &rest[semi + 1..];
+			},
+			None => {
+				unescaped.push('&');
+				rest = &rest[1..];
+			},
+		}
+	}
+	unescaped.push_str(rest);
+
+	Cow::Owned(unescaped)
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+	match name {
+		"amp" => Some('&'),
+		"lt" => Some('<'),
+		"gt" => Some('>'),
+		"quot" => Some('"'),
+		"apos" => Some('\''),
+		_ => {
+			let code = match name.strip_prefix("#x") {
+				Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+				None => name.strip_prefix('#')?.parse().ok()?,
+			};
+
+			std::char::from_u32(code)
+		},
+	}
+}
+
+fn escape_xml(string: &str, escape_quotes: bool) -> Cow<'_, str> {
+	if !string.contains(&['&', '<', '>', '"'][..]) {
+		return Cow::Borrowed(string);
+	}
+
+	let mut escaped = String::with_capacity(string.len() + 8);
+	for char in string.chars() {
+		match char {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' if escape_quotes => escaped.push_str("&quot;"),
+			c => escaped.push(c),
+		}
+	}
+
+	Cow::Owned(escaped)
+}
+
+impl<'w, W: Write> XmlWriter<'w, W> {
+	fn new(out: &'w mut W) -> Self {
+		XmlWriter { out, tags: Vec::with_capacity(10), start_tag_open: false }
+	}
+
+	fn write_declaration(&mut self) -> Result<(), Error> {
+		writeln!(self.out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")
+	}
+
+	fn write_line(&mut self, line: Line, opts: &ProgramOpts) -> Result<(), Error> {
+		match line {
+			Line::Element(path) => {
+				self.start_element(&path)?;
+			},
+			Line::Attributes(path, attrs) => {
+				self.start_element(&path)?;
+				for (name, value) in split_attributes(attrs, opts) {
+					let value = decode_value(value, opts);
+					write!(self.out, " {}=\"{}\"", name, escape_xml(&value, true))?;
+				}
+			},
+			Line::Text(path, text) => {
+				self.enter(&path)?;
+				let text = decode_value(text, opts);
+				write!(self.out, "{}", escape_xml(&text, false))?;
+			},
+			Line::CData(path, data) => {
+				self.enter(&path)?;
+				write!(self.out, "<![CDATA[{}]]>", decode_value(data, opts))?;
+			},
+			Line::Comment(path, comment) => {
+				self.enter(&path)?;
+				write!(self.out, "<!--{}-->", decode_value(comment, opts))?;
+			},
+			Line::ProcessingInstruction(path, target, data) => {
+				self.enter(&path)?;
+				match data {
+					"" => write!(self.out, "<?{}?>", target)?,
+					_ => write!(self.out, "<?{} {}?>", target, decode_value(data, opts))?,
+				}
+			},
+			Line::Namespace(path, name, uri) => {
+				// Declarations follow the line of the element they belong to
+				if !self.is_start_tag_open(&path) {
+					self.start_element(&path)?;
+				}
+
+				let uri = decode_value(uri, opts);
+				write!(self.out, " {}=\"{}\"", name, escape_xml(&uri, true))?;
+			},
+		}
+
+		Ok(())
+	}
+
+	fn finish(&mut self) -> Result<(), Error> {
+		self.close_to(0)?;
+		writeln!(self.out)
+	}
+
+	// Element and attribute lines are only printed once per element, so a line for an
+	// already open path starts a new sibling with the same name.
+	fn start_element(&mut self, path: &[&str]) -> Result<(), Error> {
+		let depth = self.common_depth(path).min(path.len() - 1);
+		self.close_to(depth)?;
+		self.open(&path[depth..])
+	}
+
+	// Text, comments and processing instructions belong to the element at `path`,
+	// reuse it if it is already open.
+	fn enter(&mut self, path: &[&str]) -> Result<(), Error> {
+		let depth = self.common_depth(path);
+		self.close_to(depth)?;
+		self.open(&path[depth..])?;
+		self.close_start_tag()
+	}
+
+	fn is_start_tag_open(&self, path: &[&str]) -> bool {
+		self.start_tag_open && self.tags.len() == path.len() && self.common_depth(path) == path.len()
+	}
+
+	fn common_depth(&self, path: &[&str]) -> usize {
+		self.tags.iter().zip(path.iter()).take_while(|(open, new)| open == *new).count()
+	}
+
+	// The open tags keep their sibling indices so that indexed siblings are told
+	// apart, the indices are only stripped when writing the names.
+	fn open(&mut self, names: &[&str]) -> Result<(), Error> {
+		for name in names {
+			self.close_start_tag()?;
+			write!(self.out, "<{}", strip_index(name))?;
+			self.tags.push(name.to_string());
+			self.start_tag_open = true;
+		}
+
+		Ok(())
+	}
+
+	fn close_to(&mut self, depth: usize) -> Result<(), Error> {
+		while self.tags.len() > depth {
+			let name = self.tags.pop().unwrap();
+			if self.start_tag_open {
+				write!(self.out, "/>")?;
+				self.start_tag_open = false;
+			} else {
+				write!(self.out, "</{}>", strip_index(&name))?;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn close_start_tag(&mut self) -> Result<(), Error> {
+		if self.start_tag_open {
+			write!(self.out, ">")?;
+			self.start_tag_open = false;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ decode_value, split_attributes, unescape_c, unescape_xml, unflatten };
+
+	use crate::config::{ EscapeMode, ProgramOpts };
+	use crate::printer::test_values::{ printed, VALUES };
+	use crate::write_nodes;
+
+	use std::path::Path;
+
+	fn flatten(file: &Path, opts: &ProgramOpts) -> Vec<u8> {
+		let mut out = Vec::new();
+		write_nodes(file.to_str().unwrap().to_owned(), &mut out, opts).unwrap();
+		out
+	}
+
+	// Flattening the unflattened output must give back the same lines
+	fn assert_round_trip(file: &Path, opts: &ProgramOpts) {
+		let flat = flatten(file, opts);
+		let mut xml = Vec::new();
+		unflatten(&flat[..], &mut xml, opts).unwrap();
+
+		let copy = std::env::temp_dir().join(format!("xmlparse-round-trip-{}-{:?}.xml", std::process::id(), std::thread::current().id()));
+		std::fs::write(&copy, &xml).unwrap();
+		let again = flatten(&copy, opts);
+		std::fs::remove_file(&copy).unwrap();
+
+		assert_eq!(String::from_utf8_lossy(&flat), String::from_utf8_lossy(&again));
+	}
+
+	fn assert_round_trip_str(document: &str) {
+		let file = std::env::temp_dir().join(format!("xmlparse-document-{}-{:?}.xml", std::process::id(), std::thread::current().id()));
+		std::fs::write(&file, document).unwrap();
+		assert_round_trip(&file, &ProgramOpts::default());
+		std::fs::remove_file(&file).unwrap();
+	}
+
+	#[test]
+	fn round_trip_short_xml() {
+		let file = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/short.xml");
+		assert_round_trip(&file, &ProgramOpts::default());
+	}
+
+	#[test]
+	fn round_trip_comment_and_pi_only_elements() {
+		assert_round_trip_str("<r><a><!--c--></a><a><?p d?></a><a><b><!--c--></b></a></r>");
+	}
+
+	#[test]
+	fn round_trip_text_only_siblings() {
+		assert_round_trip_str("<a><b>x</b><b>y</b><c>z</c>t<c>w</c></a>");
+	}
+
+	fn unflattened(lines: &str) -> String {
+		let mut xml = Vec::new();
+		unflatten(lines.as_bytes(), &mut xml, &ProgramOpts::default()).unwrap();
+		String::from_utf8(xml).unwrap()
+	}
+
+	#[test]
+	fn repeated_element_lines_start_siblings() {
+		let xml = unflattened("/a/b=\"x\"\n/a/b\n/a/b=\"y\"\n/a/c=\"z\"\n/a=\"t\"\n/a/c=\"w\"\n");
+		assert!(xml.ends_with("<a><b>x</b><b>y</b><c>z</c>t<c>w</c></a>\n"), "{}", xml);
+	}
+
+	#[test]
+	fn comments_and_pis_inside_elements() {
+		let xml = unflattened("/r\n/r/a\n/r/a/![c]\n/r/a\n/r/a/p?[d]\n/r/a\n/r/a/b\n/r/a/b/![c]\n");
+		assert!(xml.ends_with("<r><a><!--c--></a><a><?p d?></a><a><b><!--c--></b></a></r>\n"), "{}", xml);
+	}
+
+	fn assert_invalid(lines: &str) {
+		let mut xml = Vec::new();
+		let result = unflatten(lines.as_bytes(), &mut xml, &ProgramOpts::default());
+		assert!(result.is_err(), "{:?}", lines);
+	}
+
+	#[test]
+	fn empty_names_invalid() {
+		assert_invalid("/\n");
+		assert_invalid("/@[a=1]\n");
+		assert_invalid("//a\n");
+		assert_invalid("/a/[2]\n");
+		assert_invalid("/a//b=\"x\"\n");
+		assert_invalid("/a/![c]\n/a//![c]\n");
+	}
+
+	#[test]
+	fn file_names_invalid() {
+		assert_invalid("a.xml:/a\n");
+		assert_invalid("a.xml:3:1:/a=\"x\"\n");
+	}
+
+	fn opts(escape_mode: EscapeMode, escape_non_ascii: bool) -> ProgramOpts {
+		ProgramOpts { escape_mode, escape_non_ascii, ..ProgramOpts::default() }
+	}
+
+	#[test]
+	fn escape_round_trip() {
+		for escape_mode in [EscapeMode::C, EscapeMode::Xml] {
+			for escape_non_ascii in [false, true] {
+				let opts = opts(escape_mode, escape_non_ascii);
+				for value in VALUES {
+					let escaped = printed(value, &opts);
+					assert!(!escaped.contains('\n'), "{:?}", escaped);
+					assert_eq!(decode_value(&escaped, &opts), *value);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn unescape_c_sequences() {
+		assert_eq!(unescape_c("a\\nb\\tc\\rd\\\"e\\]"), "a\nb\tc\rd\"e]");
+		assert_eq!(unescape_c("\\\\"), "\\");
+		assert_eq!(unescape_c("\\u{e9}\\u{1F600}"), "é😀");
+		// Invalid or incomplete sequences are kept
+		assert_eq!(unescape_c("\\u{zz}"), "u{zz}");
+		assert_eq!(unescape_c("\\u{d800}"), "u{d800}");
+		assert_eq!(unescape_c("\\u{41"), "u{41");
+		assert_eq!(unescape_c("end\\"), "end\\");
+	}
+
+	#[test]
+	fn unescape_xml_entities() {
+		assert_eq!(unescape_xml("&lt;a&gt; &amp;amp; &quot;&apos;"), "<a> &amp; \"'");
+		assert_eq!(unescape_xml("&#65;&#x42;&#x1f600;"), "AB😀");
+		// Unknown or unterminated references are kept
+		assert_eq!(unescape_xml("&nbsp; & &#xzz; &lt"), "&nbsp; & &#xzz; &lt");
+		assert_eq!(unescape_xml("&#xd800;"), "&#xd800;");
+	}
+
+	#[test]
+	fn split_raw_attributes() {
+		let opts = ProgramOpts::default();
+		assert_eq!(split_attributes("a=1,b=2", &opts), [("a", "1"), ("b", "2")]);
+		assert_eq!(split_attributes("a=x,y,b=2", &opts), [("a", "x,y"), ("b", "2")]);
+		assert_eq!(split_attributes("a=x=y,b=", &opts), [("a", "x=y"), ("b", "")]);
+		assert_eq!(split_attributes("a=, b=c", &opts), [("a", ", b=c")]);
+		assert_eq!(split_attributes("a=", &opts), [("a", "")]);
+		assert!(split_attributes("", &opts).is_empty());
+	}
+
+	#[test]
+	fn split_escaped_attributes() {
+		let opts = opts(EscapeMode::C, false);
+		assert_eq!(split_attributes("a=x\\,b=y,c=\\\\,d=1", &opts), [("a", "x\\,b=y"), ("c", "\\\\"), ("d", "1")]);
+
+		let opts = ProgramOpts { escape_mode: EscapeMode::Xml, ..opts };
+		assert_eq!(split_attributes("a=x&#44;b&#61;y,c=1", &opts), [("a", "x&#44;b&#61;y"), ("c", "1")]);
+	}
+}
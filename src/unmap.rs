@@ -0,0 +1,151 @@
+/* xmlparse - An extensible xml processing tool that converts xml data to
+ * a line oriented format similar to that of xpath.
+ * Copyright (C) 2021 Saleh Bakra'a
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::config::{ EscapeMode, ProgramOpts };
+
+use crate::printer::escape_and_print;
+
+use std::borrow::Cow;
+use std::io::{ BufRead, Write };
+use std::io::Error;
+
+/// Reverses the whitespace mapping and compression of the lines printed with `opts`.
+/// The output is the same as if the lines were printed without `map_whitespace` and
+/// `compress_whitespace`, values are still escaped according to the escape mode.
+///
+/// Names and the syntax of the lines are printed as they are, so the characters
+/// whitespace is mapped to should not appear in names or be one of `/@=[]"`.
+pub fn unmap<R: BufRead, W: Write>(mut input: R, output: &mut W, opts: &ProgramOpts) -> Result<(), Error> {
+	let mut line = String::new();
+	loop {
+		line.clear();
+		if input.read_line(&mut line)? == 0 {
+			return Ok(());
+		}
+
+		output.write_all(unmap_string(&line, opts).as_bytes())?;
+	}
+}
+
+// Reverses the transliteration and compression done by `printer::print_string`.
+// Whitespace is escaped the way `print_string` escapes it without mapping.
+pub(crate) fn unmap_string<'a>(string: &'a str, opts: &ProgramOpts) -> Cow<'a, str> {
+	if !opts.map_whitespace && !opts.compress_whitespace {
+		return Cow::Borrowed(string);
+	}
+
+	let (space_char, tab_char, newline_char) = if opts.map_whitespace {
+		(opts.space_map, opts.tab_map, opts.newline_map)
+	} else {
+		(' ', '\t', '\n')
+	};
+	let is_map_char = |c: char| opts.map_whitespace && (c == space_char || c == tab_char || c == newline_char);
+
+	// Printing to a vector can't fail
+	let mut unmapped = Vec::with_capacity(string.len());
+	let mut buf = [0; 4];
+	let mut print = |unmapped: &mut Vec<u8>, c: char| escape_and_print(unmapped, c, opts, &mut buf).unwrap();
+
+	let mut chars = string.chars();
+	while let Some(char) = chars.next() {
+		match char {
+			c if c == tab_char && opts.compress_whitespace => {
+				(0..opts.compress_level).for_each(|_| unmapped.push(b' '));
+			},
+			c if c == space_char && opts.map_whitespace => unmapped.push(b' '),
+			c if c == tab_char && opts.map_whitespace => print(&mut unmapped, '\t'),
+			c if c == newline_char && opts.map_whitespace => print(&mut unmapped, '\n'),
+			'\\' if opts.escape_mode != EscapeMode::Xml => match chars.next() {
+				Some(c) if is_map_char(c) => print(&mut unmapped, c),
+				Some('\\') if opts.escape_mode == EscapeMode::None => unmapped.push(b'\\'),
+				Some('t') if opts.escape_mode == EscapeMode::None => unmapped.push(b'\t'),
+				// The other escapes of the escape mode are kept
+				Some(c) => {
+					unmapped.push(b'\\');
+					push_char(&mut unmapped, c);
+				},
+				None => unmapped.push(b'\\'),
+			},
+			'&' if opts.escape_mode == EscapeMode::Xml && opts.map_whitespace => {
+				let rest = chars.as_str();
+				let escaped = rest.strip_prefix("#x")
+					.and_then(|rest| rest.find(';').map(|end| &rest[..end]))
+					.and_then(|hex| u32::from_str_radix(hex, 16).ok().map(|code| (code, hex.len())))
+					.and_then(|(code, len)| std::char::from_u32(code).map(|c| (c, len)))
+					.filter(|&(c, _)| is_map_char(c));
+
+				match escaped {
+					Some((c, len)) => {
+						print(&mut unmapped, c);
+						chars = rest[len + 3..].chars();
+					},
+					None => unmapped.push(b'&'),
+				}
+			},
+			c => push_char(&mut unmapped, c),
+		}
+	}
+
+	// Only whole characters were pushed
+	Cow::Owned(String::from_utf8(unmapped).unwrap())
+}
+
+fn push_char(string: &mut Vec<u8>, c: char) {
+	string.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+	use super::unmap_string;
+
+	use crate::config::{ EscapeMode, ProgramOpts };
+	use crate::printer::test_values::{ printed, VALUES };
+
+	// Unmapping gives the value as it is printed without mapping or compression
+	fn assert_unmaps(value: &str, opts: &ProgramOpts) {
+		let plain = ProgramOpts { map_whitespace: false, compress_whitespace: false, escape_mode: opts.escape_mode, ..ProgramOpts::default() };
+		let mapped = printed(value, opts);
+		assert_eq!(unmap_string(&mapped, opts), printed(value, &plain), "{:?} printed as {:?}", value, mapped);
+	}
+
+	#[test]
+	fn unmap_mapped_whitespace() {
+		for escape_mode in [EscapeMode::None, EscapeMode::C, EscapeMode::Xml] {
+			let opts = ProgramOpts { map_whitespace: true, escape_mode, ..ProgramOpts::default() };
+			VALUES.iter().for_each(|value| assert_unmaps(value, &opts));
+		}
+	}
+
+	#[test]
+	fn unmap_compressed_whitespace() {
+		for escape_mode in [EscapeMode::None, EscapeMode::C, EscapeMode::Xml] {
+			for map_whitespace in [false, true] {
+				for compress_level in [1, 2, 4] {
+					let opts = ProgramOpts { map_whitespace, compress_whitespace: true, compress_level, escape_mode, ..ProgramOpts::default() };
+					VALUES.iter().for_each(|value| assert_unmaps(value, &opts));
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn unchanged_without_mapping() {
+		let opts = ProgramOpts::default();
+		assert_eq!(unmap_string("a\\·b", &opts), "a\\·b");
+	}
+}